@@ -0,0 +1,372 @@
+use std::rc::Rc;
+
+use strum::IntoEnumIterator;
+
+use crate::{
+    assembler::{Assembler, AssemblerOptions, Assembly},
+    ast::{ASTNode, SourceLoc, Statement},
+    diagnostics::{Diagnostic, Diagnostics},
+    opcodes::{OpCode, INSTRUCTIONS},
+    parser::{ASTParser, DIRECTIVES},
+    register::Register,
+    sources::SourceLoader,
+};
+
+/// A label as it's written in the source, either where it's defined or where it's referenced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    /// The qualified name of the label, such as `main.loop` for `.loop`
+    pub name: String,
+    /// Where the label is written
+    pub loc: SourceLoc,
+    /// The number of characters the label is written with
+    pub len: usize,
+}
+
+impl Symbol {
+    /// Determines if the given position, 1-based like [`SourceLoc`], falls on the symbol. The
+    /// position right after it counts, as that's where the cursor is after typing it.
+    fn contains(&self, line: usize, col: usize) -> bool {
+        self.loc.line == line && (self.loc.col..=self.loc.col + self.len).contains(&col)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompletionKind {
+    Mnemonic,
+    Directive,
+    Register,
+    Label,
+}
+
+/// Something that can be typed at a position in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    /// A short description, such as the operands a mnemonic takes
+    pub detail: String,
+}
+
+/// What an editor needs to know about a source file: its diagnostics, where its labels are
+/// defined and referenced, what its instructions assemble to and what can be typed in it.
+///
+/// The file is analysed as part of the program it includes. While the program doesn't parse,
+/// only the statements of the file that do are known, and nothing is assembled.
+pub struct Analysis {
+    name: Rc<str>,
+    source: String,
+    /// The statements of the program with their labels qualified
+    statements: Vec<Statement>,
+    diagnostics: Diagnostics,
+    assembly: Option<Assembly>,
+    /// The definition of every label of the program
+    definitions: Vec<Symbol>,
+    /// The references to labels in the file
+    references: Vec<Symbol>,
+}
+
+impl Analysis {
+    /// Analyses the named source, reading it and everything it includes through the loader.
+    pub fn new(loader: &impl SourceLoader, name: &str) -> Analysis {
+        let name: Rc<str> = Rc::from(name);
+        let mut diagnostics = Diagnostics::default();
+        let source = loader.load(&name).unwrap_or_default();
+        let statements = ASTParser::parse_collecting(loader, &name, &mut diagnostics)
+            .unwrap_or_else(|error| {
+                diagnostics.push(error.into());
+                vec![]
+            });
+
+        let assembly = match diagnostics.has_errors() {
+            true => None,
+            false => {
+                match Assembler::assemble_with(statements.clone(), &AssemblerOptions::default()) {
+                    Ok(mut assembly) => {
+                        diagnostics.extend(std::mem::take(&mut assembly.diagnostics));
+                        Some(assembly)
+                    }
+                    Err(errors) => {
+                        diagnostics.extend(errors);
+                        None
+                    }
+                }
+            }
+        };
+
+        let mut qualified = statements.clone();
+        Assembler::qualify_labels(&mut qualified);
+        let mut definitions = vec![];
+        let mut references = vec![];
+        for (written, stmt) in statements.iter().zip(&qualified) {
+            match (&written.node, &stmt.node) {
+                (ASTNode::Label(written), ASTNode::Label(name)) => definitions.push(Symbol {
+                    name: name.clone(),
+                    loc: stmt.loc.clone(),
+                    len: written.chars().count(),
+                }),
+                // `sys` takes syscall names, not labels
+                (ASTNode::Sys(_), _) => (),
+                _ if *stmt.loc.file == *name => {
+                    let line = source.lines().nth(stmt.loc.line - 1).unwrap_or_default();
+                    let tokens = tokens(line);
+                    // labels are found in the order they're written, after the statement starts
+                    let mut col = stmt.loc.col;
+                    for (written, label) in
+                        labels(&written.node).into_iter().zip(labels(&stmt.node))
+                    {
+                        let found = tokens
+                            .iter()
+                            .find(|(start, token)| *start >= col && *token == written);
+                        if let Some((start, token)) = found {
+                            references.push(Symbol {
+                                name: label,
+                                loc: SourceLoc::new(name.clone(), stmt.loc.line, *start),
+                                len: token.chars().count(),
+                            });
+                            col = start + 1;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Analysis {
+            name,
+            source,
+            statements: qualified,
+            diagnostics,
+            assembly,
+            definitions,
+            references,
+        }
+    }
+
+    /// Gets the diagnostics about the file, and those that aren't about any file.
+    pub fn diagnostics(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.loc.as_ref().is_none_or(|loc| loc.file == self.name))
+    }
+
+    /// Finds the label written at the given position, 1-based like [`SourceLoc`].
+    pub fn symbol_at(&self, line: usize, col: usize) -> Option<&Symbol> {
+        self.references
+            .iter()
+            .chain(self.definitions.iter().filter(|d| d.loc.file == self.name))
+            .find(|symbol| symbol.contains(line, col))
+    }
+
+    /// Finds the definition of the label at the given position, which may be in another file.
+    pub fn definition(&self, line: usize, col: usize) -> Option<&Symbol> {
+        let symbol = self.symbol_at(line, col)?;
+        self.definitions.iter().find(|d| d.name == symbol.name)
+    }
+
+    /// Finds every reference in the file to the label at the given position.
+    pub fn references(&self, line: usize, col: usize) -> Vec<&Symbol> {
+        match self.symbol_at(line, col) {
+            Some(symbol) => self
+                .references
+                .iter()
+                .filter(|r| r.name == symbol.name)
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Describes what's at the given position: the address of a label, or the opcode, encoding
+    /// and size of an instruction. Encodings are only known while the program assembles.
+    pub fn hover(&self, line: usize, col: usize) -> Option<String> {
+        if let Some(symbol) = self.symbol_at(line, col) {
+            let addr = self.assembly.as_ref()?.symbols.get(&symbol.name)?;
+            return Some(format!("`{}` at `0x{:04X}`", symbol.name, addr));
+        }
+        // the last statement starting before the position, as labels may precede instructions
+        let stmt = self
+            .statements
+            .iter()
+            .filter(|stmt| {
+                stmt.loc.file == self.name && stmt.loc.line == line && stmt.loc.col <= col
+            })
+            .last()?;
+        let bytes = self.assembly.as_ref().and_then(|assembly| {
+            let (_, range) = assembly.listing.iter().find(|(loc, _)| *loc == stmt.loc)?;
+            assembly.memory.get_buf(range.start, range.end)
+        });
+        let hex = |bytes: &[u8]| {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            hex.join(" ")
+        };
+        match (stmt.node.instruction(), bytes) {
+            (Some(_), Some(bytes)) => {
+                let opcode = OpCode::try_from(*bytes.first()?).ok()?;
+                Some(format!(
+                    "`{:?}` (`0x{:02X}`), {} bytes: `{}`",
+                    opcode,
+                    bytes[0],
+                    bytes.len(),
+                    hex(&bytes)
+                ))
+            }
+            (Some((mnemonic, args)), None) => {
+                let def = Assembler::select(mnemonic, &args, false).ok()?;
+                Some(format!(
+                    "`{:?}` (`0x{:02X}`), {} bytes",
+                    def.opcode,
+                    def.byte,
+                    def.size()
+                ))
+            }
+            (None, Some(bytes)) if !bytes.is_empty() => {
+                Some(format!("{} bytes: `{}`", bytes.len(), hex(&bytes)))
+            }
+            (None, _) => None,
+        }
+    }
+
+    /// Lists what can be typed at the given position: mnemonics and directives where a
+    /// statement starts, and registers and labels where an operand goes.
+    pub fn completions(&self, line: usize, col: usize) -> Vec<Completion> {
+        let text = self.source.lines().nth(line - 1).unwrap_or_default();
+        let before: String = text.chars().take(col - 1).collect();
+        if !strip_labels(&before)
+            .trim_start()
+            .contains(char::is_whitespace)
+        {
+            let mut completions: Vec<Completion> = vec![];
+            for def in INSTRUCTIONS {
+                let operands: Vec<String> =
+                    def.operands.iter().map(|k| format!("{:?}", k)).collect();
+                let form = format!("{} {}", def.mnemonic, operands.join(" "));
+                match completions.iter_mut().find(|c| c.label == def.mnemonic) {
+                    Some(completion) => {
+                        completion.detail = format!("{} | {}", completion.detail, form.trim_end())
+                    }
+                    None => completions.push(Completion {
+                        label: def.mnemonic.to_string(),
+                        kind: CompletionKind::Mnemonic,
+                        detail: form.trim_end().to_string(),
+                    }),
+                }
+            }
+            completions.extend(DIRECTIVES.iter().map(|directive| Completion {
+                label: directive.to_string(),
+                kind: CompletionKind::Directive,
+                detail: "directive".to_string(),
+            }));
+            return completions;
+        }
+
+        let mut completions: Vec<Completion> = Register::iter()
+            .map(|reg| Completion {
+                label: reg.as_ref().to_lowercase(),
+                kind: CompletionKind::Register,
+                detail: "register".to_string(),
+            })
+            .collect();
+        // local labels are offered under the global label the position belongs to
+        let scope = self
+            .definitions
+            .iter()
+            .filter(|d| d.loc.file == self.name && d.loc.line <= line && !d.name.contains('.'))
+            .last()
+            .map(|d| format!("{}.", d.name));
+        for definition in &self.definitions {
+            let written = match &scope {
+                Some(scope) if definition.name.starts_with(scope.as_str()) => {
+                    format!(".{}", &definition.name[scope.len()..])
+                }
+                _ => definition.name.clone(),
+            };
+            if written.contains('@') || (written.contains('.') && !written.starts_with('.')) {
+                continue;
+            }
+            let detail = match self
+                .assembly
+                .as_ref()
+                .and_then(|a| a.symbols.get(&definition.name))
+            {
+                Some(addr) => format!("label at 0x{:04X}", addr),
+                None => "label".to_string(),
+            };
+            completions.push(Completion {
+                label: written,
+                kind: CompletionKind::Label,
+                detail,
+            });
+        }
+        completions
+    }
+}
+
+/// Gets the labels referenced by the node's operands, in the order they're written.
+fn labels(node: &ASTNode) -> Vec<String> {
+    let mut node = node.clone();
+    node.args_mut()
+        .into_iter()
+        .flat_map(|arg| arg.labels_mut().into_iter().map(|label| label.clone()))
+        .collect()
+}
+
+/// Splits a line into the words it's written with, numbers included, with the 1-based column
+/// each starts at. Strings, character literals and comments are skipped.
+fn tokens(line: &str) -> Vec<(usize, &str)> {
+    let is_word = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let (at, c) = chars[i];
+        match c {
+            ';' | '#' => break,
+            '/' if line[at..].starts_with("/*") => match line[at + 2..].find("*/") {
+                Some(end) => {
+                    let end = at + 2 + end + 2;
+                    i = chars
+                        .iter()
+                        .position(|(at, _)| *at >= end)
+                        .unwrap_or(chars.len());
+                }
+                None => break,
+            },
+            '"' | '\'' => {
+                // up to the closing quote, skipping escaped characters
+                i += 1;
+                while i < chars.len() && chars[i].1 != c {
+                    i += if chars[i].1 == '\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            c if is_word(c) => {
+                let start = i;
+                while i < chars.len() && is_word(chars[i].1) {
+                    i += 1;
+                }
+                let end = chars.get(i).map_or(line.len(), |(at, _)| *at);
+                tokens.push((start + 1, &line[at..end]));
+            }
+            _ => i += 1,
+        }
+    }
+    tokens
+}
+
+/// Removes the labels a line starts with, such as `loop:` in `loop: inc r1`.
+fn strip_labels(mut text: &str) -> &str {
+    loop {
+        let trimmed = text.trim_start();
+        match trimmed.split_once(':') {
+            Some((label, rest))
+                if !label.is_empty()
+                    && label
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || "_-.".contains(c)) =>
+            {
+                text = rest
+            }
+            _ => return text,
+        }
+    }
+}
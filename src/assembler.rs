@@ -1,419 +1,627 @@
-use std::{collections::HashMap, num::ParseIntError};
+use std::{
+    collections::{HashMap, HashSet},
+    num::ParseIntError,
+    ops::Range,
+    rc::Rc,
+};
+
+use strum::IntoEnumIterator;
 
 use crate::{
-    ast::{ASTArg, ASTNode},
+    ast::{ASTArg, ASTNode, SourceLoc, Statement},
+    diagnostics::{did_you_mean, suggest, Diagnostic, Diagnostics},
+    image::Image,
     memory::{Memory, MemoryBuilder},
-    opcodes::OpCode,
+    opcodes::{self, IndexedOperand, InstructionDef, Operand, OperandKind},
+    optimizer::{OptLevel, Optimizer},
     parser,
+    register::Register,
+    syscalls::SyscallRegistry,
 };
 
 pub struct Assembler;
 
-// macro to get index of register
-macro_rules! reg_i {
-    ($arg:expr) => {
-        $arg.to_index() as u8
-    };
+/// Options that change how [`Assembler::assemble_with`] behaves.
+#[derive(Debug, Clone, Default)]
+pub struct AssemblerOptions {
+    /// Treat warnings as errors
+    pub werror: bool,
+    /// Emit position-independent code: labels are referenced relative to the instruction using
+    /// them, and absolute references to labels are errors
+    pub pic: bool,
+    /// The syscalls that `sys` can call by name
+    pub syscalls: SyscallRegistry,
+    /// How much the program is optimized before being assembled
+    pub opt_level: OptLevel,
+}
+
+/// The result of a successful assembly.
+pub struct Assembly {
+    pub memory: Memory,
+    /// The ranges of memory the program occupies
+    pub used: Vec<Range<usize>>,
+    /// The address execution starts at
+    pub entry: u16,
+    /// The address of every label, by its qualified name
+    pub symbols: HashMap<String, u16>,
+    /// The qualified names of the labels, in the order they're defined
+    labels: Vec<String>,
+    /// The memory every instruction and data directive was assembled into, with its location
+    pub listing: Vec<(SourceLoc, Range<usize>)>,
+    /// The warnings found while assembling
+    pub diagnostics: Diagnostics,
+}
+
+impl Assembly {
+    /// Creates a compact image holding only the memory the program occupies.
+    pub fn to_image(&self) -> Image {
+        Image::from_memory(&self.memory, &self.used, self.entry)
+    }
+
+    /// Gets every label with its address, in the order the labels are defined, so that labels
+    /// sharing an address keep their source order.
+    pub fn labels(&self) -> impl Iterator<Item = (&String, &u16)> {
+        self.labels
+            .iter()
+            .map(|label| (label, &self.symbols[label]))
+    }
 }
 
 impl Assembler {
-    pub fn assemble(input: Vec<ASTNode>) -> Result<Memory, AssemblerError> {
+    pub fn assemble(input: Vec<Statement>) -> Result<Memory, AssemblerError> {
+        Ok(Self::assemble_with(input, &AssemblerOptions::default())?.memory)
+    }
+
+    /// Assembles the program, collecting every error and warning found along the way. Fails with
+    /// all of the collected diagnostics if any of them is an error.
+    pub fn assemble_with(
+        mut input: Vec<Statement>,
+        options: &AssemblerOptions,
+    ) -> Result<Assembly, Diagnostics> {
+        let mut diagnostics = Diagnostics::new(options.werror);
+        Self::qualify_labels(&mut input);
+        // where each line is in the program, with included sources in place of their `.include`
+        let mut positions: HashMap<(Rc<str>, usize), usize> = HashMap::new();
+        for (i, stmt) in input.iter().enumerate() {
+            positions
+                .entry((stmt.loc.file.clone(), stmt.loc.line))
+                .or_insert(i);
+        }
+        let input = Optimizer::optimize(input, options.opt_level, &mut diagnostics);
         let mut builder = MemoryBuilder::new(Memory::default());
-        // the labels encountered so far
-        let mut label_addrs: HashMap<String, u16> = HashMap::new();
+        // the labels encountered so far, with where they were defined
+        let mut label_addrs: HashMap<String, (u16, SourceLoc)> = HashMap::new();
+        // the labels in the order they were defined, used to report unused labels in order
+        let mut label_order: Vec<String> = vec![];
         // the pending jumps/calls that need to be patched with the correct label address
-        let mut need_patching: Vec<(String, usize)> = vec![];
-        for node in input {
+        let mut need_patching: Vec<(Patch, SourceLoc)> = vec![];
+        // the sections started by `.org`, the first one implicitly starting at address 0
+        let mut sections: Vec<Section> = vec![Section {
+            start: 0,
+            end: 0,
+            loc: None,
+        }];
+        // the `.entry` directive, if any
+        let mut entry: Option<(ASTArg, SourceLoc)> = None;
+        let mut listing = vec![];
+        for Statement { node, loc } in input {
             match node {
-                ASTNode::Label(name) => {
-                    let addr = builder.get_counter() as u16;
-                    label_addrs.insert(name, addr);
+                ASTNode::Org(ASTArg::Lit(addr)) if (addr as usize) < builder.len() => {
+                    sections.last_mut().unwrap().end = builder.get_counter();
+                    sections.push(Section {
+                        start: addr as usize,
+                        end: addr as usize,
+                        loc: Some(loc),
+                    });
+                    builder.set_counter(addr as usize);
+                    continue;
                 }
-                ASTNode::Mov(a1, a2) => match (&a1, &a2) {
-                    (ASTArg::Lit(lit), ASTArg::Reg(reg)) => {
-                        builder.push(OpCode::MovLitReg.into());
-                        builder.push_u16(*lit);
-                        builder.push(reg_i!(reg));
-                    }
-                    (ASTArg::Reg(reg1), ASTArg::Reg(reg2)) => {
-                        builder.push(OpCode::MovRegReg.into());
-                        builder.push(reg_i!(reg1));
-                        builder.push(reg_i!(reg2));
+                ASTNode::Org(arg) => {
+                    diagnostics.push(AssemblerError::InvalidArgument(arg).at(loc).into());
+                    continue;
+                }
+                ASTNode::Entry(arg) => {
+                    match &entry {
+                        Some((_, prev)) => diagnostics.error(
+                            format!("Duplicate entry (previously set at {})", prev),
+                            Some(loc),
+                        ),
+                        None => entry = Some((arg, loc)),
                     }
-                    (ASTArg::Reg(reg1), ASTArg::Mem(mem)) => {
-                        builder.push(OpCode::MovRegMem.into());
-                        builder.push(reg_i!(reg1));
-                        match &**mem {
-                            ASTArg::Label(label) => {
-                                need_patching.push((label.to_string(), builder.get_counter()));
-                                builder.incr();
-                            }
-                            ASTArg::Lit(lit) => {
-                                builder.push_u16(*lit);
-                            }
-                            ASTArg::Reg(ptrreg) => {
-                                builder.push(reg_i!(ptrreg));
-                            }
-                            _ => return Err(AssemblerError::InvalidArgument(a2)),
+                    continue;
+                }
+                ASTNode::Comment(_) => continue,
+                _ => (),
+            }
+            if let ASTNode::Label(name) = node {
+                if let Some((_, prev)) = label_addrs.get(&name) {
+                    diagnostics.error(
+                        format!(
+                            "Duplicate label: {} (previously defined at {})",
+                            source_name(&name),
+                            prev
+                        ),
+                        Some(loc),
+                    );
+                } else {
+                    let addr = builder.get_counter() as u16;
+                    label_addrs.insert(name.clone(), (addr, loc));
+                    label_order.push(name);
+                }
+                continue;
+            }
+            let mut patches = vec![];
+            let start = builder.get_counter();
+            let assembled = Self::assemble_node(
+                node,
+                &mut builder,
+                &mut patches,
+                &mut diagnostics,
+                &loc,
+                options,
+            );
+            match assembled {
+                Ok(()) => {
+                    need_patching.extend(patches.into_iter().map(|p| (p, loc.clone())));
+                    listing.push((loc, start..builder.get_counter()));
+                }
+                Err(e) => diagnostics.push(e.at(loc).into()),
+            }
+        }
+
+        // patching moves the counter back, so the last section ends here
+        sections.last_mut().unwrap().end = builder.get_counter();
+        let mut used: HashSet<String> = HashSet::new();
+        for (patch, loc) in need_patching {
+            match label_addrs.get(&patch.label) {
+                Some((addr, _)) => {
+                    builder.set_counter(patch.at);
+                    let addr = addr.wrapping_add(patch.addend);
+                    builder.push_u16(match patch.relative_to {
+                        Some(base) => addr.wrapping_sub(base as u16),
+                        None => addr,
+                    });
+                    used.insert(patch.label);
+                }
+                None => diagnostics.push(Self::unknown_label(patch.label, &label_addrs, loc)),
+            }
+        }
+
+        Self::check_overlaps(&mut sections, &mut diagnostics);
+
+        // without an `.entry`, execution starts at the first section holding any code
+        let entry = match entry {
+            Some((ASTArg::Lit(addr), _)) => addr,
+            Some((ASTArg::Label(label), loc)) => match label_addrs.get(&label) {
+                Some((addr, _)) => {
+                    used.insert(label);
+                    *addr
+                }
+                None => {
+                    diagnostics.push(Self::unknown_label(label, &label_addrs, loc));
+                    0
+                }
+            },
+            Some((arg, loc)) => {
+                diagnostics.push(AssemblerError::InvalidArgument(arg).at(loc).into());
+                0
+            }
+            None => sections
+                .iter()
+                .find(|section| section.start < section.end)
+                .map_or(0, |section| section.start as u16),
+        };
+
+        for label in label_order.iter().filter(|label| !used.contains(*label)) {
+            let loc = label_addrs[label].1.clone();
+            diagnostics.warn(format!("Unused label: {}", source_name(label)), Some(loc));
+        }
+
+        // the passes report what they find in turn, so their diagnostics are put in program order
+        diagnostics.sort_by_key(|diagnostic| match &diagnostic.loc {
+            Some(loc) => {
+                let line = (loc.file.clone(), loc.line);
+                (positions.get(&line).copied().unwrap_or(usize::MAX), loc.col)
+            }
+            None => (usize::MAX, 0),
+        });
+        if diagnostics.has_errors() {
+            return Err(diagnostics);
+        }
+        Ok(Assembly {
+            used: builder.used_ranges(),
+            memory: builder.build(),
+            entry,
+            symbols: label_addrs
+                .into_iter()
+                .map(|(label, (addr, _))| (label, addr))
+                .collect(),
+            labels: label_order,
+            listing,
+            diagnostics,
+        })
+    }
+
+    /// Rewrites local labels into names that are unique across the whole program.
+    ///
+    /// A `.name` label belongs to the closest global label defined before it, and is renamed to
+    /// `global.name`, so the same local name can be reused under every global label. Numeric
+    /// labels such as `1:` may be defined any number of times; `1b` refers to the closest
+    /// definition before the reference and `1f` to the closest one after it, without crossing
+    /// into another global label.
+    /// References that can't be resolved are left untouched, to be reported as invalid labels.
+    pub fn qualify_labels(input: &mut [Statement]) {
+        // the global label each statement belongs to
+        let mut scopes = Vec::with_capacity(input.len());
+        let mut scope = String::new();
+        for stmt in input.iter() {
+            if let ASTNode::Label(name) = &stmt.node {
+                if !name.starts_with('.') && !is_numeric_label(name) {
+                    scope = name.clone();
+                }
+            }
+            scopes.push(scope.clone());
+        }
+
+        // every definition of a numeric label, as (statement index, label)
+        let numeric_defs: Vec<(usize, String)> = input
+            .iter()
+            .enumerate()
+            .filter_map(|(i, stmt)| match &stmt.node {
+                ASTNode::Label(name) if is_numeric_label(name) => Some((i, name.clone())),
+                _ => None,
+            })
+            .collect();
+        // numeric labels are made unique by the index of the statement defining them
+        let numeric_name = |def: usize, num: &str| format!("{}.{}@{}", scopes[def], num, def);
+
+        for (i, stmt) in input.iter_mut().enumerate() {
+            let scope = &scopes[i];
+            if let ASTNode::Label(name) = &mut stmt.node {
+                if is_numeric_label(name) {
+                    *name = numeric_name(i, name);
+                } else if let Some(local) = name.strip_prefix('.') {
+                    *name = format!("{}.{}", scope, local);
+                }
+                continue;
+            }
+            for arg in stmt.node.args_mut() {
+                for label in arg.labels_mut() {
+                    if let Some(local) = label.strip_prefix('.') {
+                        *label = format!("{}.{}", scope, local);
+                    } else if let Some((num, forward)) = numeric_reference(label) {
+                        let mut candidates = numeric_defs
+                            .iter()
+                            .filter(|(def, name)| *name == num && scopes[*def] == *scope);
+                        let def = match forward {
+                            true => candidates.find(|(def, _)| *def > i),
+                            false => candidates.rev().find(|(def, _)| *def < i),
+                        };
+                        if let Some((def, _)) = def {
+                            *label = numeric_name(*def, &num);
                         }
                     }
-                    (ASTArg::Mem(mem), ASTArg::Reg(reg2)) => {
-                        match &**mem {
-                            ASTArg::Label(label) => {
-                                builder.push(OpCode::MovMemReg.into());
-                                need_patching.push((label.to_string(), builder.get_counter()));
-                                builder.incr();
-                            }
-                            ASTArg::Lit(lit) => {
-                                builder.push(OpCode::MovMemReg.into());
-                                builder.push_u16(*lit);
-                            }
-                            ASTArg::Reg(reg) => {
-                                builder.push(OpCode::MovRegPtrReg.into());
-                                builder.push(reg_i!(reg));
+                }
+            }
+        }
+    }
+
+    /// Reports a reference to a label that isn't defined, suggesting a register or a label with
+    /// a close name, as registers misspelled in operands read as labels.
+    fn unknown_label(
+        label: String,
+        label_addrs: &HashMap<String, (u16, SourceLoc)>,
+        loc: SourceLoc,
+    ) -> Diagnostic {
+        let registers = register_names();
+        let mut labels: Vec<&str> = label_addrs.keys().map(|label| source_name(label)).collect();
+        labels.sort();
+        let candidates = registers.iter().map(String::as_str).chain(labels);
+        let hint = did_you_mean(suggest(source_name(&label), candidates));
+        let mut diagnostic = Diagnostic::from(AssemblerError::InvalidLabel(label).at(loc));
+        diagnostic.message.push_str(&hint);
+        diagnostic
+    }
+
+    /// Reports every pair of sections that share some of their addresses.
+    fn check_overlaps(sections: &mut [Section], diagnostics: &mut Diagnostics) {
+        sections.sort_by_key(|section| section.start);
+        let non_empty: Vec<&Section> = sections.iter().filter(|s| s.start < s.end).collect();
+        for (i, section) in non_empty.iter().enumerate() {
+            for other in &non_empty[i + 1..] {
+                if other.start >= section.end {
+                    break;
+                }
+                diagnostics.error(
+                    format!(
+                        "Section 0x{:04X}..0x{:04X} overlaps section 0x{:04X}..0x{:04X}{}",
+                        other.start,
+                        other.end,
+                        section.start,
+                        section.end,
+                        section
+                            .loc
+                            .as_ref()
+                            .map_or(String::new(), |loc| format!(" at {}", loc))
+                    ),
+                    other.loc.clone(),
+                );
+            }
+        }
+    }
+
+    /// Encodes a single instruction into the builder, using the instruction table entry whose
+    /// operand kinds accept its arguments. Label operands are recorded in `need_patching`
+    /// together with the index their address has to be written to, and warnings are reported to
+    /// `diagnostics` at `loc`.
+    fn assemble_node(
+        node: ASTNode,
+        builder: &mut MemoryBuilder,
+        need_patching: &mut Vec<Patch>,
+        diagnostics: &mut Diagnostics,
+        loc: &SourceLoc,
+        options: &AssemblerOptions,
+    ) -> Result<(), AssemblerError> {
+        // syscalls may be called by name
+        let node = match node {
+            ASTNode::Sys(ASTArg::Label(name)) => match options.syscalls.by_name(&name) {
+                Some(syscall) => ASTNode::Sys(ASTArg::Lit(syscall.number as u16)),
+                None => return Err(AssemblerError::UnknownSyscall(name)),
+            },
+            node => node,
+        };
+        let (mnemonic, args) = match &node {
+            ASTNode::Include(path) => {
+                return Err(AssemblerError::Parser(format!(
+                    "Unresolved include: {}",
+                    path
+                )))
+            }
+            ASTNode::Byte(args) => {
+                let len = args
+                    .iter()
+                    .map(|arg| match arg {
+                        ASTArg::Str(bytes) => bytes.len(),
+                        _ => 1,
+                    })
+                    .sum();
+                Self::check_space(builder, len)?;
+                for arg in args {
+                    match arg {
+                        ASTArg::Str(bytes) => {
+                            for byte in bytes {
+                                builder.push(*byte);
                             }
-                            _ => return Err(AssemblerError::InvalidArgument(a2)),
-                        }
-                        builder.push(reg_i!(reg2));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(a1)),
-                },
-                ASTNode::Add(a, reg) => match reg {
-                    ASTArg::Reg(reg) => match a {
-                        ASTArg::Reg(reg2) => {
-                            builder.push(OpCode::AddRegReg.into());
-                            builder.push(reg_i!(reg2));
-                            builder.push(reg_i!(reg));
-                        }
-                        ASTArg::Lit(lit) => {
-                            builder.push(OpCode::AddLitReg.into());
-                            builder.push_u16(lit);
-                            builder.push(reg_i!(reg));
-                        }
-                        _ => return Err(AssemblerError::InvalidArgument(a)),
-                    },
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::Sub(a1, a2) => match (&a1, &a2) {
-                    (ASTArg::Reg(r1), ASTArg::Reg(r2)) => {
-                        builder.push(OpCode::SubRegReg.into());
-                        builder.push(reg_i!(r1));
-                        builder.push(reg_i!(r2));
-                    }
-                    (ASTArg::Reg(r1), ASTArg::Lit(lit)) => {
-                        builder.push(OpCode::SubRegLit.into());
-                        builder.push(reg_i!(r1));
-                        builder.push_u16(*lit);
-                    }
-                    (ASTArg::Lit(lit), ASTArg::Reg(r2)) => {
-                        builder.push(OpCode::SubRegLit.into());
-                        builder.push_u16(*lit);
-                        builder.push(reg_i!(r2));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(a1)), // lazy zzzz
-                },
-                ASTNode::Mul(a, reg) => match reg {
-                    ASTArg::Reg(reg) => match a {
-                        ASTArg::Reg(reg2) => {
-                            builder.push(OpCode::MulRegReg.into());
-                            builder.push(reg_i!(reg2));
-                            builder.push(reg_i!(reg));
-                        }
-                        ASTArg::Lit(lit) => {
-                            builder.push(OpCode::MulLitReg.into());
-                            builder.push_u16(lit);
-                            builder.push(reg_i!(reg));
-                        }
-                        _ => return Err(AssemblerError::InvalidArgument(a)),
-                    },
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::Shl(reg, a) => match reg {
-                    ASTArg::Reg(reg) => match a {
-                        ASTArg::Reg(reg2) => {
-                            builder.push(OpCode::ShlRegReg.into());
-                            builder.push(reg_i!(reg));
-                            builder.push(reg_i!(reg2));
-                        }
-                        ASTArg::Lit(lit) => {
-                            builder.push(OpCode::ShlRegLit.into());
-                            builder.push(reg_i!(reg));
-                            builder.push_u16(lit);
-                        }
-                        _ => return Err(AssemblerError::InvalidArgument(a)),
-                    },
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::Shr(reg, a) => match reg {
-                    ASTArg::Reg(reg) => match a {
-                        ASTArg::Reg(reg2) => {
-                            builder.push(OpCode::ShrRegReg.into());
-                            builder.push(reg_i!(reg));
-                            builder.push(reg_i!(reg2));
                         }
-                        ASTArg::Lit(lit) => {
-                            builder.push(OpCode::ShrRegLit.into());
-                            builder.push(reg_i!(reg));
-                            builder.push_u16(lit);
+                        arg => {
+                            builder.push(Self::immediate(arg, 8, diagnostics, loc)? as u8);
                         }
-                        _ => return Err(AssemblerError::InvalidArgument(a)),
-                    },
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::And(reg, a) => match reg {
-                    ASTArg::Reg(reg) => match a {
-                        ASTArg::Reg(reg2) => {
-                            builder.push(OpCode::AndRegReg.into());
-                            builder.push(reg_i!(reg));
-                            builder.push(reg_i!(reg2));
-                        }
-                        ASTArg::Lit(lit) => {
-                            builder.push(OpCode::AndRegLit.into());
-                            builder.push(reg_i!(reg));
-                            builder.push_u16(lit);
-                        }
-                        _ => return Err(AssemblerError::InvalidArgument(a)),
-                    },
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::Or(reg, a) => match reg {
-                    ASTArg::Reg(reg) => match a {
-                        ASTArg::Reg(reg2) => {
-                            builder.push(OpCode::OrRegReg.into());
-                            builder.push(reg_i!(reg));
-                            builder.push(reg_i!(reg2));
-                        }
-                        ASTArg::Lit(lit) => {
-                            builder.push(OpCode::OrRegLit.into());
-                            builder.push(reg_i!(reg));
-                            builder.push_u16(lit);
-                        }
-                        _ => return Err(AssemblerError::InvalidArgument(a)),
-                    },
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::Not(reg) => match reg {
-                    ASTArg::Reg(r) => {
-                        builder.push(OpCode::NotReg.into());
-                        builder.push(reg_i!(r));
                     }
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::Xor(reg, a) => match reg {
-                    ASTArg::Reg(reg) => match a {
-                        ASTArg::Lit(lit) => {
-                            builder.push(OpCode::XorRegLit.into());
-                            builder.push(reg_i!(reg));
-                            builder.push_u16(lit);
+                }
+                return Ok(());
+            }
+            ASTNode::Table(args) => {
+                Self::check_space(builder, args.len() * 2)?;
+                for arg in args {
+                    match arg {
+                        ASTArg::Label(label) => {
+                            if options.pic {
+                                return Err(AssemblerError::AbsoluteReference(label.clone()));
+                            }
+                            need_patching.push(Patch::absolute(label, builder.get_counter()));
+                            builder.push_u16(0);
                         }
-                        ASTArg::Reg(reg2) => {
-                            builder.push(OpCode::XorRegLit.into());
-                            builder.push(reg_i!(reg));
-                            builder.push(reg_i!(reg2));
+                        arg => {
+                            builder.push_u16(Self::immediate(arg, 16, diagnostics, loc)?);
                         }
-                        _ => return Err(AssemblerError::InvalidArgument(a)),
-                    },
-                    _ => return Err(AssemblerError::InvalidArgument(reg)),
-                },
-                ASTNode::Jne(label, a) => match label {
-                    ASTArg::Label(label) => {
-                        match a {
-                            ASTArg::Lit(lit) => {
-                                builder.push(OpCode::JmpNELit.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push_u16(lit);
-                            }
-                            ASTArg::Reg(reg) => {
-                                builder.push(OpCode::JmpNEReg.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push(reg_i!(reg));
-                            }
-                            _ => return Err(AssemblerError::InvalidArgument(a)),
-                        };
-                        need_patching.push((label, builder.get_counter() - 2));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(label)),
-                },
-                ASTNode::Jeq(label, a) => match label {
-                    ASTArg::Label(label) => {
-                        match a {
-                            ASTArg::Lit(lit) => {
-                                builder.push(OpCode::JmpEQLit.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push_u16(lit);
-                            }
-                            ASTArg::Reg(reg) => {
-                                builder.push(OpCode::JmpEQReg.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push(reg_i!(reg));
-                            }
-                            _ => return Err(AssemblerError::InvalidArgument(a)),
-                        };
-                        need_patching.push((label, builder.get_counter() - 2));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(label)),
-                },
-                ASTNode::Jlt(label, a) => match label {
-                    ASTArg::Label(label) => {
-                        match a {
-                            ASTArg::Lit(lit) => {
-                                builder.push(OpCode::JmpLTLit.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push_u16(lit);
-                            }
-                            ASTArg::Reg(reg) => {
-                                builder.push(OpCode::JmpLTReg.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push(reg_i!(reg));
-                            }
-                            _ => return Err(AssemblerError::InvalidArgument(a)),
-                        };
-                        need_patching.push((label, builder.get_counter() - 2));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(label)),
-                },
-                ASTNode::Jgt(label, a) => match label {
-                    ASTArg::Label(label) => {
-                        match a {
-                            ASTArg::Lit(lit) => {
-                                builder.push(OpCode::JmpGTLit.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push_u16(lit);
-                            }
-                            ASTArg::Reg(reg) => {
-                                builder.push(OpCode::JmpGTReg.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push(reg_i!(reg));
-                            }
-                            _ => return Err(AssemblerError::InvalidArgument(a)),
-                        };
-                        need_patching.push((label, builder.get_counter() - 2));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(label)),
-                },
-                ASTNode::Jle(label, a) => match label {
-                    ASTArg::Label(label) => {
-                        match a {
-                            ASTArg::Lit(lit) => {
-                                builder.push(OpCode::JmpLELit.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push_u16(lit);
-                            }
-                            ASTArg::Reg(reg) => {
-                                builder.push(OpCode::JmpLEReg.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push(reg_i!(reg));
-                            }
-                            _ => return Err(AssemblerError::InvalidArgument(a)),
-                        };
-                        need_patching.push((label, builder.get_counter() - 2));
                     }
-                    _ => return Err(AssemblerError::InvalidArgument(label)),
-                },
-                ASTNode::Jge(label, a) => match label {
-                    ASTArg::Label(label) => {
-                        match a {
-                            ASTArg::Lit(lit) => {
-                                builder.push(OpCode::JmpGELit.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push_u16(lit);
-                            }
-                            ASTArg::Reg(reg) => {
-                                builder.push(OpCode::JmpGEReg.into());
-                                builder.set_counter(builder.get_counter() + 1);
-                                builder.push(reg_i!(reg));
-                            }
-                            _ => return Err(AssemblerError::InvalidArgument(a)),
-                        };
-                        need_patching.push((label, builder.get_counter() - 2));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(label)),
-                },
-                ASTNode::Jmp(label) => match label {
-                    ASTArg::Label(label) => {
-                        builder.push(OpCode::Jmp.into());
-                        need_patching.push((label, builder.get_counter()));
-                        builder.incr();
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(label)),
-                },
-                ASTNode::Psh(a) => match a {
-                    ASTArg::Lit(lit) => {
-                        builder.push(OpCode::PshLit.into());
-                        builder.push_u16(lit);
-                    }
-                    ASTArg::Reg(reg) => {
-                        builder.push(OpCode::PshReg.into());
-                        builder.push(reg_i!(reg));
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(a)),
-                },
-                ASTNode::Pop(reg) => {
-                    builder.push(OpCode::Pop.into());
-                    match reg {
-                        ASTArg::Reg(reg) => builder.push(reg_i!(reg)),
-                        _ => return Err(AssemblerError::InvalidArgument(reg)),
-                    };
                 }
-                ASTNode::Cal(a) => match a {
-                    ASTArg::Lit(lit) => {
-                        builder.push(OpCode::CalLit.into());
-                        builder.push_u16(lit);
-                    }
-                    ASTArg::Reg(reg) => {
-                        builder.push(OpCode::CalReg.into());
-                        builder.push(reg_i!(reg));
-                    }
-                    ASTArg::Label(label) => {
-                        builder.push(OpCode::CalLit.into());
-                        need_patching.push((label, builder.get_counter()));
-                        builder.incr();
-                    }
-                    _ => return Err(AssemblerError::InvalidArgument(a)),
-                },
-                ASTNode::Inc(reg) => {
-                    builder.push(OpCode::IncReg.into());
-                    match reg {
-                        ASTArg::Reg(reg) => builder.push(reg_i!(reg)),
-                        _ => return Err(AssemblerError::InvalidArgument(reg)),
-                    };
+                return Ok(());
+            }
+            node => node
+                .instruction()
+                .expect("labels and layout directives are handled by Assembler::assemble_with"),
+        };
+        let def = Self::select(mnemonic, &args, options.pic)?;
+        Self::check_space(builder, def.size())?;
+
+        let start = builder.get_counter();
+        let mut operands = Vec::with_capacity(args.len());
+        for (i, arg) in args.into_iter().enumerate() {
+            // memory operands are encoded as the address or register inside the brackets
+            let arg = match arg {
+                ASTArg::Mem(inner) => &**inner,
+                arg => arg,
+            };
+            let operand = match arg {
+                ASTArg::Reg(reg) => Operand::Reg(*reg),
+                ASTArg::Lit(_) | ASTArg::Neg(_) | ASTArg::Char(_) => {
+                    let bits = def.operands[i].size() * 8;
+                    Operand::Value(Self::immediate(arg, bits, diagnostics, loc)?)
                 }
-                ASTNode::Dec(reg) => {
-                    builder.push(OpCode::DecReg.into());
-                    match reg {
-                        ASTArg::Reg(reg) => builder.push(reg_i!(reg)),
-                        _ => return Err(AssemblerError::InvalidArgument(reg)),
-                    };
+                ASTArg::Label(label) => {
+                    let at = start + def.operand_offset(i);
+                    need_patching.push(match def.operands[i] {
+                        // relative to the address right after the instruction
+                        OperandKind::Rel => Patch {
+                            label: label.clone(),
+                            at,
+                            relative_to: Some(start + def.size()),
+                            addend: 0,
+                        },
+                        _ if options.pic => {
+                            return Err(AssemblerError::AbsoluteReference(label.clone()))
+                        }
+                        _ => Patch::absolute(label, at),
+                    });
+                    Operand::Value(0)
                 }
-                ASTNode::Sys(val) => {
-                    builder.push(OpCode::SysLit.into());
-                    match val {
-                        ASTArg::Lit(lit) => builder.push(lit as u8),
-                        ASTArg::Label(_) => todo!("Here, we should make some kind of functin that converts function names to u8"),
-                        _ => return Err(AssemblerError::InvalidArgument(val)),
-                    };
+                // relative operands count from the end of the instruction
+                ASTArg::Rel(offset) => {
+                    Operand::Value((*offset as u16).wrapping_sub(def.size() as u16))
                 }
-                ASTNode::Ret => {
-                    builder.push(OpCode::Ret.into());
+                ASTArg::Indexed(address) => {
+                    let displacement = address.displacement as u16;
+                    if let Some(label) = &address.label {
+                        if options.pic {
+                            return Err(AssemblerError::AbsoluteReference(label.clone()));
+                        }
+                        // the displacement follows the registers and the scale
+                        need_patching.push(Patch {
+                            addend: displacement,
+                            ..Patch::absolute(label, start + def.operand_offset(i) + 2)
+                        });
+                    }
+                    Operand::Indexed(IndexedOperand {
+                        base: address.base,
+                        index: address.index.map(|(reg, _)| reg),
+                        scale: address.index.map_or(1, |(_, scale)| scale),
+                        displacement,
+                    })
                 }
-                ASTNode::Hlt => {
-                    builder.push(OpCode::Hlt.into());
+                arg => return Err(AssemblerError::InvalidArgument(arg.clone())),
+            };
+            operands.push(operand);
+        }
+        for byte in def.encode(&operands) {
+            builder.push(byte);
+        }
+        Ok(())
+    }
+
+    /// Makes sure `len` bytes fit in memory from the counter on.
+    fn check_space(builder: &MemoryBuilder, len: usize) -> Result<(), AssemblerError> {
+        let start = builder.get_counter();
+        match start + len > builder.len() {
+            true => Err(AssemblerError::OutOfMemory(start)),
+            false => Ok(()),
+        }
+    }
+
+    /// Encodes a literal operand in the given number of bits. Unsigned literals too large for a
+    /// byte are truncated with a warning, as they always were, while characters and negative
+    /// numbers out of range are errors.
+    fn immediate(
+        arg: &ASTArg,
+        bits: usize,
+        diagnostics: &mut Diagnostics,
+        loc: &SourceLoc,
+    ) -> Result<u16, AssemblerError> {
+        let out_of_range = || AssemblerError::OutOfRange(arg.clone(), bits);
+        match arg {
+            ASTArg::Lit(lit) => {
+                if bits == 8 && *lit > u8::MAX as u16 {
+                    diagnostics.warn(
+                        format!(
+                            "Value {} doesn't fit in a byte, truncated to {}",
+                            lit, *lit as u8
+                        ),
+                        Some(loc.clone()),
+                    );
                 }
-                ASTNode::Nop => {
-                    builder.push(OpCode::Nop.into());
+                Ok(*lit)
+            }
+            ASTArg::Char(c) if (*c as u32) < 1 << bits => Ok(*c as u16),
+            // the most negative value of n bits is -2^(n-1)
+            ASTArg::Neg(magnitude) if *magnitude <= 1 << (bits - 1) => {
+                Ok((*magnitude as u16).wrapping_neg() & (u16::MAX >> (16 - bits)))
+            }
+            ASTArg::Char(_) | ASTArg::Neg(_) => Err(out_of_range()),
+            arg => Err(AssemblerError::InvalidArgument(arg.clone())),
+        }
+    }
+
+    /// Finds the instruction table entry for the mnemonic whose operand kinds accept the given
+    /// arguments, preferring relative encodings when emitting position-independent code and
+    /// absolute ones otherwise. Fails with the first argument no entry accepts.
+    pub(crate) fn select(
+        mnemonic: &str,
+        args: &[&ASTArg],
+        pic: bool,
+    ) -> Result<&'static InstructionDef, AssemblerError> {
+        let mut candidates: Vec<&InstructionDef> = opcodes::by_mnemonic(mnemonic)
+            .filter(|def| def.operands.len() == args.len())
+            .collect();
+        for (i, arg) in args.iter().enumerate() {
+            candidates.retain(|def| def.operands[i].accepts(arg));
+            if candidates.is_empty() {
+                // registers misspelled in operands read as labels
+                if let ASTArg::Label(label) = arg {
+                    let registers = register_names();
+                    if let Some(reg) = suggest(label, registers.iter().map(String::as_str)) {
+                        return Err(AssemblerError::MisspelledRegister(
+                            label.clone(),
+                            reg.to_string(),
+                        ));
+                    }
                 }
-            };
+                return Err(AssemblerError::InvalidArgument((*arg).clone()));
+            }
         }
+        candidates
+            .iter()
+            .find(|def| def.operands.contains(&OperandKind::Rel) == pic)
+            .or_else(|| candidates.first())
+            .copied()
+            .ok_or_else(|| AssemblerError::Parser(format!("Unknown instruction: {}", mnemonic)))
+    }
+}
+
+/// A label operand, to be written with the address of the label once it's known.
+struct Patch {
+    label: String,
+    /// The index the address is written to
+    at: usize,
+    /// The address the label is referenced relative to, for relative operands
+    relative_to: Option<usize>,
+    /// Added to the address, such as the displacement in `[table + 2]`
+    addend: u16,
+}
 
-        for (label, mem_idx) in need_patching {
-            let addr = label_addrs
-                .get(&label)
-                .ok_or(AssemblerError::InvalidLabel(label))?;
-            builder.set_counter(mem_idx);
-            builder.push_u16(*addr);
+impl Patch {
+    fn absolute(label: &str, at: usize) -> Patch {
+        Patch {
+            label: label.to_string(),
+            at,
+            relative_to: None,
+            addend: 0,
         }
+    }
+}
+
+/// A run of code placed by `.org`, with the location of the directive that started it.
+struct Section {
+    start: usize,
+    end: usize,
+    loc: Option<SourceLoc>,
+}
 
-        Ok(builder.build())
+/// Gets the names of the registers as they're written in source.
+fn register_names() -> Vec<String> {
+    Register::iter()
+        .map(|reg| reg.as_ref().to_lowercase())
+        .collect()
+}
+
+/// Gets the name a qualified label was written as in the source, e.g. `1` for `main.1@4`.
+fn source_name(label: &str) -> &str {
+    match label.split_once('@') {
+        Some((qualified, _)) => qualified.rsplit('.').next().unwrap(),
+        None => label,
+    }
+}
+
+/// Determines if the label is a numeric local label, such as `1`.
+pub(crate) fn is_numeric_label(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Splits a numeric label reference such as `1f` into the label and whether it looks forward.
+fn numeric_reference(reference: &str) -> Option<(String, bool)> {
+    let (num, direction) = reference.split_at(reference.len().checked_sub(1)?);
+    match direction {
+        "f" | "b" if is_numeric_label(num) => Some((num.to_string(), direction == "f")),
+        _ => None,
     }
 }
 
@@ -422,7 +630,35 @@ pub enum AssemblerError {
     Parser(String),
     Io(std::io::Error),
     InvalidLabel(String),
-    InvalidArgument(ASTArg), // the node and the argument that was invalid
+    InvalidArgument(ASTArg),   // the node and the argument that was invalid
+    AbsoluteReference(String), // a label referenced by its address in position-independent code
+    UnknownSyscall(String),
+    OutOfRange(ASTArg, usize), // a literal and the number of bits it had to fit in
+    MisspelledRegister(String, String), // an argument and the register it's close to
+    OutOfMemory(usize),        // the address of code that doesn't fit before the end of memory
+    IncludeNotFound(String, SourceLoc),
+    RecursiveInclude(Vec<String>, SourceLoc), // the chain of files that lead back to itself
+    Located(SourceLoc, Box<AssemblerError>),
+    Diagnostics(Diagnostics), // everything reported by a failed run
+}
+
+impl AssemblerError {
+    /// Attaches a source location to the error, unless it already carries one.
+    pub fn at(self, loc: SourceLoc) -> AssemblerError {
+        match self {
+            AssemblerError::IncludeNotFound(..)
+            | AssemblerError::RecursiveInclude(..)
+            | AssemblerError::Located(..)
+            | AssemblerError::Diagnostics(..) => self,
+            _ => AssemblerError::Located(loc, Box::new(self)),
+        }
+    }
+}
+
+impl From<Diagnostics> for AssemblerError {
+    fn from(diagnostics: Diagnostics) -> Self {
+        AssemblerError::Diagnostics(diagnostics)
+    }
 }
 
 impl From<std::io::Error> for AssemblerError {
@@ -452,6 +688,29 @@ impl std::fmt::Display for AssemblerError {
             AssemblerError::InvalidArgument(arg) => {
                 write!(f, "Invalid argument: {:?}", arg)
             }
+            AssemblerError::AbsoluteReference(label) => write!(
+                f,
+                "Absolute reference to label {} in position-independent code",
+                source_name(label)
+            ),
+            AssemblerError::UnknownSyscall(name) => write!(f, "Unknown syscall: {}", name),
+            AssemblerError::MisspelledRegister(arg, reg) => {
+                write!(f, "Invalid argument: {}, did you mean `{}`?", arg, reg)
+            }
+            AssemblerError::OutOfRange(arg, bits) => {
+                write!(f, "Value {} doesn't fit in {} bits", arg, bits)
+            }
+            AssemblerError::OutOfMemory(addr) => {
+                write!(f, "Code runs past the end of memory at 0x{:04X}", addr)
+            }
+            AssemblerError::IncludeNotFound(path, loc) => {
+                write!(f, "{}: Include not found: {}", loc, path)
+            }
+            AssemblerError::RecursiveInclude(chain, loc) => {
+                write!(f, "{}: Recursive include: {}", loc, chain.join(" -> "))
+            }
+            AssemblerError::Located(loc, e) => write!(f, "{}: {}", loc, e),
+            AssemblerError::Diagnostics(diagnostics) => write!(f, "{}", diagnostics),
         }
     }
 }
@@ -1,3 +1,5 @@
+use std::rc::Rc;
+
 use crate::register::Register;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -29,13 +31,312 @@ pub enum ASTNode {
     Ret,
     Hlt,
     Nop,
+    /// `.include "path"`, replaced by the contents of the file once includes are resolved
+    Include(String),
+    /// `.org addr`, places the code that follows at the given address
+    Org(ASTArg),
+    /// `.entry label`, sets the address execution starts at
+    Entry(ASTArg),
+    /// `.byte 1 2 3`, places the given bytes as they are
+    Byte(Vec<ASTArg>),
+    /// `.table first second`, places the 16 bit addresses of the given labels
+    Table(Vec<ASTArg>),
+    /// A comment, with its delimiters, kept so that the source can be reproduced
+    Comment(String),
+}
+
+impl ASTNode {
+    /// Builds an instruction node out of its mnemonic and operands. Returns `None` if there is no
+    /// instruction with that mnemonic taking that many operands.
+    pub fn from_instruction(mnemonic: &str, args: Vec<ASTArg>) -> Option<ASTNode> {
+        use ASTNode::*;
+        let mut args = args.into_iter();
+        let (a, b) = (args.next(), args.next());
+        if args.next().is_some() {
+            return None;
+        }
+        let node = match (mnemonic.to_lowercase().as_str(), a, b) {
+            ("mov", Some(a), Some(b)) => Mov(a, b),
+            ("add", Some(a), Some(b)) => Add(a, b),
+            ("sub", Some(a), Some(b)) => Sub(a, b),
+            ("mul", Some(a), Some(b)) => Mul(a, b),
+            ("shl", Some(a), Some(b)) => Shl(a, b),
+            ("shr", Some(a), Some(b)) => Shr(a, b),
+            ("and", Some(a), Some(b)) => And(a, b),
+            ("or", Some(a), Some(b)) => Or(a, b),
+            ("xor", Some(a), Some(b)) => Xor(a, b),
+            ("jne", Some(a), Some(b)) => Jne(a, b),
+            ("jeq", Some(a), Some(b)) => Jeq(a, b),
+            ("jlt", Some(a), Some(b)) => Jlt(a, b),
+            ("jgt", Some(a), Some(b)) => Jgt(a, b),
+            ("jle", Some(a), Some(b)) => Jle(a, b),
+            ("jge", Some(a), Some(b)) => Jge(a, b),
+            ("not", Some(a), None) => Not(a),
+            ("jmp", Some(a), None) => Jmp(a),
+            ("psh", Some(a), None) => Psh(a),
+            ("pop", Some(a), None) => Pop(a),
+            ("cal", Some(a), None) => Cal(a),
+            ("inc", Some(a), None) => Inc(a),
+            ("dec", Some(a), None) => Dec(a),
+            ("sys", Some(a), None) => Sys(a),
+            ("ret", None, None) => Ret,
+            ("hlt", None, None) => Hlt,
+            ("nop", None, None) => Nop,
+            _ => return None,
+        };
+        Some(node)
+    }
+
+    /// Splits an instruction node into its mnemonic and operands, in source order. Returns `None`
+    /// for nodes that aren't instructions, such as labels and directives.
+    pub fn instruction(&self) -> Option<(&'static str, Vec<&ASTArg>)> {
+        use ASTNode::*;
+        let instruction = match self {
+            Mov(a, b) => ("mov", vec![a, b]),
+            Add(a, b) => ("add", vec![a, b]),
+            Sub(a, b) => ("sub", vec![a, b]),
+            Mul(a, b) => ("mul", vec![a, b]),
+            Shl(a, b) => ("shl", vec![a, b]),
+            Shr(a, b) => ("shr", vec![a, b]),
+            And(a, b) => ("and", vec![a, b]),
+            Or(a, b) => ("or", vec![a, b]),
+            Xor(a, b) => ("xor", vec![a, b]),
+            Jne(a, b) => ("jne", vec![a, b]),
+            Jeq(a, b) => ("jeq", vec![a, b]),
+            Jlt(a, b) => ("jlt", vec![a, b]),
+            Jgt(a, b) => ("jgt", vec![a, b]),
+            Jle(a, b) => ("jle", vec![a, b]),
+            Jge(a, b) => ("jge", vec![a, b]),
+            Not(a) => ("not", vec![a]),
+            Jmp(a) => ("jmp", vec![a]),
+            Psh(a) => ("psh", vec![a]),
+            Pop(a) => ("pop", vec![a]),
+            Cal(a) => ("cal", vec![a]),
+            Inc(a) => ("inc", vec![a]),
+            Dec(a) => ("dec", vec![a]),
+            Sys(a) => ("sys", vec![a]),
+            Ret => ("ret", vec![]),
+            Hlt => ("hlt", vec![]),
+            Nop => ("nop", vec![]),
+            Label(_) | Include(_) | Org(_) | Entry(_) | Byte(_) | Table(_) | Comment(_) => {
+                return None
+            }
+        };
+        Some(instruction)
+    }
+
+    /// Gets mutable references to the operands of the node, in source order.
+    pub fn args_mut(&mut self) -> Vec<&mut ASTArg> {
+        use ASTNode::*;
+        match self {
+            Mov(a, b)
+            | Add(a, b)
+            | Sub(a, b)
+            | Mul(a, b)
+            | Shl(a, b)
+            | Shr(a, b)
+            | And(a, b)
+            | Or(a, b)
+            | Xor(a, b)
+            | Jne(a, b)
+            | Jeq(a, b)
+            | Jlt(a, b)
+            | Jgt(a, b)
+            | Jle(a, b)
+            | Jge(a, b) => vec![a, b],
+            Not(a) | Jmp(a) | Psh(a) | Pop(a) | Cal(a) | Inc(a) | Dec(a) | Sys(a) | Org(a)
+            | Entry(a) => vec![a],
+            Byte(args) | Table(args) => args.iter_mut().collect(),
+            Label(_) | Ret | Hlt | Nop | Include(_) | Comment(_) => vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for ASTNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ASTNode::Label(name) => write!(f, "{}:", name),
+            ASTNode::Comment(text) => write!(f, "{}", text),
+            ASTNode::Include(path) => {
+                write!(f, ".include ")?;
+                write_string(f, path.as_bytes())
+            }
+            ASTNode::Org(addr) => write!(f, ".org {}", addr),
+            ASTNode::Entry(label) => write!(f, ".entry {}", label),
+            ASTNode::Byte(args) | ASTNode::Table(args) => {
+                let name = match self {
+                    ASTNode::Byte(_) => ".byte",
+                    _ => ".table",
+                };
+                write!(f, "{}", name)?;
+                args.iter().try_for_each(|arg| write!(f, " {}", arg))
+            }
+            node => {
+                let (mnemonic, args) = node.instruction().unwrap();
+                write!(f, "{}", mnemonic)?;
+                args.iter().try_for_each(|arg| write!(f, " {}", arg))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ASTArg {
     Label(String),
     Lit(u16),
+    /// A negative decimal literal such as `-5`, holding its magnitude. It's encoded in two's
+    /// complement, and must fit in the signed range of the operand
+    Neg(u32),
+    /// A character literal such as `'A'` or `'\n'`, encoded as its code point
+    Char(char),
+    /// The bytes of a string literal, which UTF-8 encodes its characters. Only data directives
+    /// accept strings
+    Str(Vec<u8>),
     Reg(Register),
     Mem(Box<ASTArg>),
-    Offset(Box<ASTArg>, Box<ASTArg>),
+    /// A memory location computed from registers, a label and a displacement, as in
+    /// `[r1 + r2*2 - 4]` or `[table + 2]`
+    Indexed(Box<Address>),
+    /// An address relative to the start of the instruction, as in `$-6`, or `$` for the
+    /// instruction itself. Only relative operands accept it
+    Rel(i32),
+}
+
+impl ASTArg {
+    /// Gets mutable references to every label name referenced by the argument.
+    pub fn labels_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            ASTArg::Label(label) => vec![label],
+            ASTArg::Lit(_)
+            | ASTArg::Neg(_)
+            | ASTArg::Char(_)
+            | ASTArg::Str(_)
+            | ASTArg::Reg(_)
+            | ASTArg::Rel(_) => vec![],
+            ASTArg::Mem(inner) => inner.labels_mut(),
+            ASTArg::Indexed(address) => address.label.iter_mut().collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for ASTArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ASTArg::Label(name) => write!(f, "{}", name),
+            ASTArg::Lit(value) => write!(f, "0x{:X}", value),
+            ASTArg::Neg(magnitude) => write!(f, "-{}", magnitude),
+            ASTArg::Char(c) => match c {
+                '\'' => write!(f, "'\\''"),
+                '"' => write!(f, "'\"'"),
+                c => write!(f, "'{}'", escape(*c)),
+            },
+            ASTArg::Str(bytes) => write_string(f, bytes),
+            ASTArg::Reg(reg) => write!(f, "{}", reg.as_ref().to_lowercase()),
+            ASTArg::Mem(inner) => write!(f, "[{}]", inner),
+            ASTArg::Indexed(address) => write!(f, "{}", address),
+            ASTArg::Rel(0) => write!(f, "$"),
+            ASTArg::Rel(offset) if *offset < 0 => write!(f, "$-0x{:X}", offset.unsigned_abs()),
+            ASTArg::Rel(offset) => write!(f, "$+0x{:X}", offset),
+        }
+    }
+}
+
+/// The parts of an [`ASTArg::Indexed`] memory location, which is at
+/// `base + index * scale + label + displacement`. Any of them may be left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Address {
+    pub base: Option<Register>,
+    /// The index register, with the scale of 1, 2 or 4 it's multiplied by
+    pub index: Option<(Register, u8)>,
+    /// A label whose address is added to the location
+    pub label: Option<String>,
+    pub displacement: i32,
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut parts = vec![];
+        parts.extend(self.base.map(|reg| reg.as_ref().to_lowercase()));
+        parts.extend(self.index.map(|(reg, scale)| match scale {
+            1 => reg.as_ref().to_lowercase(),
+            scale => format!("{}*{}", reg.as_ref().to_lowercase(), scale),
+        }));
+        parts.extend(self.label.clone());
+        write!(f, "[{}", parts.join(" + "))?;
+        // a lone term would read back as a plain memory location, so it keeps its displacement
+        let lone = parts.len() == 1 && self.index.is_none_or(|(_, scale)| scale == 1);
+        match self.displacement {
+            0 if !lone && !parts.is_empty() => (),
+            displacement if parts.is_empty() => write!(f, "0x{:X} + 0x0", displacement as u16)?,
+            displacement if displacement < 0 => write!(f, " - 0x{:X}", -displacement)?,
+            displacement => write!(f, " + 0x{:X}", displacement)?,
+        }
+        write!(f, "]")
+    }
+}
+
+/// Writes the bytes as a string literal, escaping the characters that need it and the bytes
+/// that aren't valid UTF-8.
+fn write_string(f: &mut std::fmt::Formatter, bytes: &[u8]) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                c => write!(f, "{}", escape(c))?,
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(f, "\\x{:02X}", byte)?;
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Escapes the character for a character or string literal, leaving quotes as they are. Only
+/// ASCII control characters are written as `\x` escapes, since in strings those stand for single
+/// bytes rather than the UTF-8 encoding of a character.
+fn escape(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\0' => "\\0".to_string(),
+        '\\' => "\\\\".to_string(),
+        c if c.is_ascii_control() => format!("\\x{:02X}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+/// A position in a source file, used to point diagnostics at the code that produced them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLoc {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl SourceLoc {
+    pub fn new(file: Rc<str>, line: usize, col: usize) -> SourceLoc {
+        SourceLoc { file, line, col }
+    }
+}
+
+impl std::fmt::Display for SourceLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// An AST node together with the place in the source it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub node: ASTNode,
+    pub loc: SourceLoc,
+}
+
+impl Statement {
+    pub fn new(node: ASTNode, loc: SourceLoc) -> Statement {
+        Statement { node, loc }
+    }
 }
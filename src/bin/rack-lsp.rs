@@ -0,0 +1,313 @@
+//! A language server for `.rack` assembly, speaking LSP over stdin and stdout. It gives editors
+//! diagnostics as files are edited, goto-definition and find-references for labels, hover with
+//! the encoding of instructions and completion of mnemonics, registers and labels.
+
+use std::{collections::HashMap, error::Error};
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as LspNotification, PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, References, Request as LspRequest},
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ReferenceParams, ServerCapabilities,
+    TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use rustystack::{
+    analysis::{Analysis, CompletionKind, Symbol},
+    ast::SourceLoc,
+    diagnostics::Severity,
+    sources::{FileLoader, Overlay},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// The files open in the editor, by URL, with their unsaved contents.
+struct Server {
+    documents: HashMap<Url, String>,
+}
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    let mut server = Server {
+        documents: HashMap::new(),
+    };
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                let response = server.handle_request(request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                for notification in server.handle_notification(notification)? {
+                    connection
+                        .sender
+                        .send(Message::Notification(notification))?;
+                }
+            }
+            Message::Response(_) => (),
+        }
+    }
+    io_threads.join()?;
+    Ok(())
+}
+
+impl Server {
+    /// Updates the open documents, returning the diagnostics to publish.
+    fn handle_notification(&mut self, notification: Notification) -> Result<Vec<Notification>> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                self.documents
+                    .insert(uri.clone(), params.text_document.text);
+                uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                let uri = params.text_document.uri;
+                // full sync, so the last change holds the whole document
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(uri.clone(), change.text);
+                }
+                uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                return Ok(vec![publish(params.text_document.uri, vec![])]);
+            }
+            _ => return Ok(vec![]),
+        };
+        let diagnostics = match self.analyse(&uri) {
+            Some(analysis) => analysis
+                .diagnostics()
+                .map(|diagnostic| self.to_diagnostic(diagnostic))
+                .collect(),
+            None => vec![],
+        };
+        Ok(vec![publish(uri, diagnostics)])
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            GotoDefinition::METHOD => self.definition(request),
+            References::METHOD => self.references(request),
+            HoverRequest::METHOD => self.hover(request),
+            Completion::METHOD => self.completion(request),
+            method => {
+                return Response::new_err(
+                    id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("Unsupported request: {}", method),
+                )
+            }
+        };
+        match result {
+            Ok(value) => Response::new_ok(id, value),
+            Err(error) => invalid_params(id, error),
+        }
+    }
+
+    fn definition(&self, request: Request) -> Result<serde_json::Value> {
+        let params: GotoDefinitionParams = serde_json::from_value(request.params)?;
+        let (analysis, line, col) = self.analyse_at(&params.text_document_position_params)?;
+        let location = analysis
+            .definition(line, col)
+            .and_then(|symbol| self.location(symbol))
+            .map(GotoDefinitionResponse::Scalar);
+        Ok(serde_json::to_value(location)?)
+    }
+
+    fn references(&self, request: Request) -> Result<serde_json::Value> {
+        let params: ReferenceParams = serde_json::from_value(request.params)?;
+        let (analysis, line, col) = self.analyse_at(&params.text_document_position)?;
+        let mut symbols = analysis.references(line, col);
+        if params.context.include_declaration {
+            symbols.extend(analysis.definition(line, col));
+        }
+        let locations: Vec<Location> = symbols
+            .into_iter()
+            .filter_map(|symbol| self.location(symbol))
+            .collect();
+        Ok(serde_json::to_value(locations)?)
+    }
+
+    fn hover(&self, request: Request) -> Result<serde_json::Value> {
+        let params: HoverParams = serde_json::from_value(request.params)?;
+        let (analysis, line, col) = self.analyse_at(&params.text_document_position_params)?;
+        let hover = analysis.hover(line, col).map(|text| Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: None,
+        });
+        Ok(serde_json::to_value(hover)?)
+    }
+
+    fn completion(&self, request: Request) -> Result<serde_json::Value> {
+        let params: CompletionParams = serde_json::from_value(request.params)?;
+        let (analysis, line, col) = self.analyse_at(&params.text_document_position)?;
+        let items: Vec<CompletionItem> = analysis
+            .completions(line, col)
+            .into_iter()
+            .map(|completion| CompletionItem {
+                label: completion.label,
+                kind: Some(match completion.kind {
+                    CompletionKind::Mnemonic => CompletionItemKind::KEYWORD,
+                    CompletionKind::Directive => CompletionItemKind::KEYWORD,
+                    CompletionKind::Register => CompletionItemKind::VARIABLE,
+                    CompletionKind::Label => CompletionItemKind::REFERENCE,
+                }),
+                detail: Some(completion.detail),
+                ..CompletionItem::default()
+            })
+            .collect();
+        Ok(serde_json::to_value(CompletionResponse::Array(items))?)
+    }
+
+    /// Analyses the document the request is about, returning the 1-based position it's at.
+    fn analyse_at(&self, params: &TextDocumentPositionParams) -> Result<(Analysis, usize, usize)> {
+        let uri = &params.text_document.uri;
+        let analysis = self.analyse(uri).ok_or("The document isn't open")?;
+        let position = params.position;
+        let line = self.documents[uri]
+            .lines()
+            .nth(position.line as usize)
+            .unwrap_or_default();
+        Ok((
+            analysis,
+            position.line as usize + 1,
+            to_char_col(line, position.character),
+        ))
+    }
+
+    /// Gets a line of a source, from the editor if it's open there and from disk otherwise.
+    fn line(&self, file: &str, line: usize) -> Option<String> {
+        let open = Url::from_file_path(file)
+            .ok()
+            .and_then(|uri| self.documents.get(&uri));
+        let source = match open {
+            Some(source) => source.clone(),
+            None => std::fs::read_to_string(file).ok()?,
+        };
+        source
+            .lines()
+            .nth(line.saturating_sub(1))
+            .map(str::to_string)
+    }
+
+    /// Converts a 1-based location, `len` characters wide, into the 0-based range editors use.
+    fn range(&self, loc: &SourceLoc, len: usize) -> Range {
+        let line = self.line(&loc.file, loc.line).unwrap_or_default();
+        let position =
+            |col: usize| Position::new(loc.line.saturating_sub(1) as u32, to_utf16_col(&line, col));
+        Range::new(position(loc.col), position(loc.col + len))
+    }
+
+    fn location(&self, symbol: &Symbol) -> Option<Location> {
+        Some(Location::new(
+            Url::from_file_path(&*symbol.loc.file).ok()?,
+            self.range(&symbol.loc, symbol.len),
+        ))
+    }
+
+    fn to_diagnostic(&self, diagnostic: &rustystack::diagnostics::Diagnostic) -> Diagnostic {
+        // diagnostics that aren't about any place are shown at the start of the file
+        let start = diagnostic
+            .loc
+            .as_ref()
+            .map_or(Position::new(0, 0), |loc| self.range(loc, 0).start);
+        Diagnostic {
+            range: Range::new(start, Position::new(start.line + 1, 0)),
+            severity: Some(match diagnostic.severity {
+                Severity::Error => DiagnosticSeverity::ERROR,
+                Severity::Warning => DiagnosticSeverity::WARNING,
+                Severity::Note => DiagnosticSeverity::INFORMATION,
+            }),
+            source: Some("rack".to_string()),
+            message: diagnostic.message.clone(),
+            ..Diagnostic::default()
+        }
+    }
+
+    /// Analyses an open document, with the files it includes read from disk.
+    fn analyse(&self, uri: &Url) -> Option<Analysis> {
+        let source = self.documents.get(uri)?;
+        let name = match uri.to_file_path() {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(_) => uri.to_string(),
+        };
+        let files = FileLoader {
+            include_paths: vec![],
+        };
+        let loader = Overlay {
+            loader: &files,
+            name: name.clone(),
+            source: source.clone(),
+        };
+        Some(Analysis::new(&loader, &name))
+    }
+}
+
+fn publish(uri: Url, diagnostics: Vec<Diagnostic>) -> Notification {
+    let params = PublishDiagnosticsParams {
+        uri,
+        diagnostics,
+        version: None,
+    };
+    Notification::new(PublishDiagnostics::METHOD.to_string(), params)
+}
+
+fn invalid_params(id: RequestId, error: Box<dyn Error + Send + Sync>) -> Response {
+    Response::new_err(
+        id,
+        lsp_server::ErrorCode::InvalidParams as i32,
+        error.to_string(),
+    )
+}
+
+/// Converts a 1-based column counted in characters into the 0-based column editors use, counted
+/// in UTF-16 code units as LSP positions are by default.
+fn to_utf16_col(line: &str, col: usize) -> u32 {
+    let before: String = line.chars().take(col.saturating_sub(1)).collect();
+    // columns past the end of the line, such as the end of the last word, count as characters
+    let past_end = col.saturating_sub(1) - before.chars().count();
+    (before.encode_utf16().count() + past_end) as u32
+}
+
+/// Converts a 0-based column counted in UTF-16 code units into a 1-based one in characters.
+fn to_char_col(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    let mut chars = 0;
+    for c in line.chars() {
+        if units >= character as usize {
+            return chars + 1;
+        }
+        units += c.len_utf16();
+        chars += 1;
+    }
+    chars + 1 + (character as usize).saturating_sub(units)
+}
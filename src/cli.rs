@@ -0,0 +1,240 @@
+//! The command line of the `rustystack` program: its commands, their options and their help.
+
+use std::path::{Path, PathBuf};
+
+use crate::{assembler::AssemblerOptions, optimizer::OptLevel};
+
+pub const USAGE: &str = "usage: rustystack <command> [options] <file>
+
+commands:
+    assemble   Assemble a source file into an image
+    run        Assemble and run a source file, or run an image
+    debug      Run a source file or an image under an interactive debugger
+    gdb        Run a source file or an image under gdb or lldb, connecting over TCP
+    disasm     Disassemble an image, or a source file once assembled
+    check      Report the problems in a source file without producing anything
+    fmt        Rewrite a source file in canonical form
+    syscalls   List the syscalls programs can call
+
+A file given without a command is run. Run `rustystack <command> --help` for the options of a
+command.
+
+exit status: 0 on success, 1 if the file has errors or the program fails, 2 on bad usage. run,
+debug and gdb exit with the status the program halts with instead: the value of acc on `hlt` or
+`sys exit`, or 255 if it doesn't fit in a byte. A program exiting with 1 or 2 can't be told
+apart from a failure by the status alone, only failures print an error on stderr.";
+
+const ASSEMBLER_OPTIONS: &str = "
+    -I <dir>          Search <dir> for included files, after the directory of the including file
+    -Werror           Treat warnings as errors
+    -fpic             Assemble position-independent code
+    -O0, -O1, -O2     Set the optimization level";
+
+/// The commands, with their usage and whether they take the assembler options.
+const COMMANDS: &[(&str, &str, bool)] = &[
+    (
+        "assemble",
+        "usage: rustystack assemble [options] <file.rack> -o <image.bin>
+
+options:
+    -o <image.bin>    Write the image to <image.bin>",
+        true,
+    ),
+    (
+        "run",
+        "usage: rustystack run [options] <file.rack | image.bin>
+
+Exits with the status the program halts with, the value of acc on `hlt` or `sys exit`. Like
+failures, programs may exit with 1 or 2, but only failures print an error on stderr.
+
+options:
+    --trace <file>    Write a JSON record of every executed instruction to <file>, one per line
+    --trace-filter <label | start..end>
+                      Only trace the instructions in the given place, may be repeated",
+        true,
+    ),
+    (
+        "debug",
+        "usage: rustystack debug [options] <file.rack | image.bin>
+
+Runs the program under an interactive debugger. Type `help` at the prompt for its commands.
+
+options:",
+        true,
+    ),
+    (
+        "gdb",
+        "usage: rustystack gdb [options] <file.rack | image.bin>
+
+Waits for a debugger speaking the GDB remote protocol to connect on 127.0.0.1, as with
+`target remote :1234` in gdb, then runs the program under its control. Registers and memory
+are big-endian, so gdb may need `set endian big`.
+
+options:
+    --port <port>     Listen on <port>, 1234 by default",
+        true,
+    ),
+    (
+        "disasm",
+        "usage: rustystack disasm [options] <file.rack | image.bin>
+
+options:",
+        true,
+    ),
+    (
+        "check",
+        "usage: rustystack check [options] <file.rack>
+
+options:",
+        true,
+    ),
+    (
+        "fmt",
+        "usage: rustystack fmt [--check] <file.rack>
+
+options:
+    --check           Fail if the file isn't formatted, instead of formatting it",
+        false,
+    ),
+    ("syscalls", "usage: rustystack syscalls", false),
+];
+
+/// The port `gdb` listens on without `--port`, the one gdb's documentation uses.
+const DEFAULT_PORT: u16 = 1234;
+
+/// Why a command didn't succeed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// The command line is wrong, the message says how
+    Usage(String),
+    /// The command failed, the message says why
+    Error(String),
+    /// The command failed, and has already reported why
+    Reported,
+}
+
+/// What the command line asks for.
+#[derive(Debug)]
+pub enum Parsed {
+    /// The help of the program or of a command, to be printed
+    Help(String),
+    Command(Args),
+}
+
+/// The command line arguments shared by every command.
+#[derive(Debug)]
+pub struct Args {
+    pub command: &'static str,
+    pub file: String,
+    pub output: Option<String>,
+    pub include_paths: Vec<PathBuf>,
+    pub options: AssemblerOptions,
+    /// Only check that the file is formatted, instead of formatting it
+    pub check: bool,
+    /// Where to write the trace of the program, when it's traced
+    pub trace: Option<String>,
+    /// The places in the program the trace is restricted to
+    pub trace_filters: Vec<String>,
+    /// The TCP port to wait for a debugger on
+    pub port: u16,
+}
+
+impl Args {
+    /// Parses the command line arguments, without the name of the program.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Parsed, Failure> {
+        let mut args = args.into_iter().peekable();
+        let command = match args.peek().map(String::as_str) {
+            None => return Err(Failure::Usage("no command given".to_string())),
+            Some("-h") | Some("--help") | Some("help") => {
+                return Ok(Parsed::Help(USAGE.to_string()))
+            }
+            Some(arg) => match COMMANDS.iter().find(|(name, ..)| *name == arg) {
+                Some(command) => {
+                    args.next();
+                    command
+                }
+                None if arg.starts_with('-') || Path::new(arg).is_file() => &COMMANDS[1],
+                None => return Err(Failure::Usage(format!("unknown command `{}`", arg))),
+            },
+        };
+        let &(name, _, assembles) = command;
+        let mut parsed = Args {
+            command: name,
+            file: String::new(),
+            output: None,
+            include_paths: vec![],
+            options: AssemblerOptions::default(),
+            check: false,
+            trace: None,
+            trace_filters: vec![],
+            port: DEFAULT_PORT,
+        };
+        let mut file = None;
+        let missing = |flag: &str| Failure::Usage(format!("`{}` needs a value", flag));
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Parsed::Help(Args::usage(command))),
+                "-Werror" if assembles => parsed.options.werror = true,
+                "-fpic" if assembles => parsed.options.pic = true,
+                "-O0" if assembles => parsed.options.opt_level = OptLevel::O0,
+                "-O1" if assembles => parsed.options.opt_level = OptLevel::O1,
+                "-O2" if assembles => parsed.options.opt_level = OptLevel::O2,
+                "-o" if name == "assemble" => {
+                    parsed.output = Some(args.next().ok_or(missing("-o"))?)
+                }
+                "--check" if name == "fmt" => parsed.check = true,
+                "--trace" if name == "run" => {
+                    parsed.trace = Some(args.next().ok_or(missing("--trace"))?)
+                }
+                "--trace-filter" if name == "run" => parsed
+                    .trace_filters
+                    .push(args.next().ok_or(missing("--trace-filter"))?),
+                "--port" if name == "gdb" => {
+                    let port = args.next().ok_or(missing("--port"))?;
+                    parsed.port = port
+                        .parse()
+                        .map_err(|_| Failure::Usage(format!("invalid port `{}`", port)))?
+                }
+                _ => match arg.strip_prefix("-I").filter(|_| assembles) {
+                    Some("") => {
+                        let dir = args.next().ok_or(missing("-I"))?;
+                        parsed.include_paths.push(PathBuf::from(dir))
+                    }
+                    Some(dir) => parsed.include_paths.push(PathBuf::from(dir)),
+                    None if arg.starts_with('-') => {
+                        return Err(Failure::Usage(format!(
+                            "unknown option `{}` for {}",
+                            arg, name
+                        )))
+                    }
+                    None if file.is_some() => {
+                        return Err(Failure::Usage(format!("unexpected argument `{}`", arg)))
+                    }
+                    None => file = Some(arg),
+                },
+            }
+        }
+        match file {
+            Some(_) if name == "syscalls" => {
+                return Err(Failure::Usage("syscalls takes no file".to_string()))
+            }
+            Some(file) => parsed.file = file,
+            None if name == "syscalls" => (),
+            None => return Err(Failure::Usage(format!("{} needs a file", name))),
+        }
+        if name == "assemble" && parsed.output.is_none() {
+            return Err(Failure::Usage(
+                "assemble needs an output file, given with `-o`".to_string(),
+            ));
+        }
+        Ok(Parsed::Command(parsed))
+    }
+
+    /// Gets the help of a command, with the assembler options for those that assemble.
+    fn usage(&(_, usage, assembles): &(&str, &str, bool)) -> String {
+        match assembles {
+            true => format!("{}{}", usage, ASSEMBLER_OPTIONS),
+            false => usage.to_string(),
+        }
+    }
+}
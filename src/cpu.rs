@@ -1,9 +1,11 @@
-use std::{cell::RefCell, io::BufRead};
+use std::cell::{Cell, RefCell};
 
 use crate::{
+    image::Image,
     memory::{InspectableAddr, Memory},
-    opcodes::OpCode,
+    opcodes::{OpCode, Operand, OperandKind},
     register::Register,
+    syscalls::SyscallRegistry,
     to_u16, REGISTER_SIZE,
 };
 use strum::{EnumCount, IntoEnumIterator};
@@ -14,12 +16,28 @@ use strum::{EnumCount, IntoEnumIterator};
 pub struct CPU {
     memory: Memory,
     registers_memory: Memory,
+    syscalls: SyscallRegistry,
+    /// The exit status of the program, once it has halted
+    exit_status: Cell<Option<u16>>,
+    /// How the last instruction stepped through used memory
+    effects: RefCell<Effects>,
+}
+
+/// What executing an instruction did, besides changing registers. Fetching the instruction
+/// itself doesn't count as reading memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Effects {
+    /// The bytes the instruction read as data, by the address they start at
+    pub reads: Vec<(u16, Vec<u8>)>,
+    /// The bytes the instruction wrote, by the address they start at
+    pub writes: Vec<(u16, Vec<u8>)>,
+    /// The number of the syscall the instruction called
+    pub syscall: Option<u8>,
 }
 
 impl CPU {
     /// Creates a new CPU with the given memory buffer.
     pub fn new(memory: Memory) -> CPU {
-        let memory = memory;
         let registers = Memory::new((Register::COUNT * REGISTER_SIZE).try_into().unwrap());
 
         // set stack and base pointer to max mem
@@ -33,9 +51,35 @@ impl CPU {
         CPU {
             memory,
             registers_memory: registers,
+            syscalls: SyscallRegistry::default(),
+            exit_status: Cell::new(None),
+            effects: RefCell::new(Effects::default()),
         }
     }
 
+    /// Makes `sys` dispatch through the given registry instead of the builtin syscalls.
+    pub fn with_syscalls(mut self, syscalls: SyscallRegistry) -> CPU {
+        self.syscalls = syscalls;
+        self
+    }
+
+    /// Loads the segments of the image into memory, and points the ip register at its entry.
+    pub fn load_image(&self, image: &Image) -> Result<(), CpuError> {
+        image.load_into(&self.memory)?;
+        self.set_register(&Register::IP, image.entry);
+        Ok(())
+    }
+
+    /// Gets the memory the program runs in.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Gets the syscalls `sys` dispatches through.
+    pub fn syscalls(&self) -> &SyscallRegistry {
+        &self.syscalls
+    }
+
     /// Gets the value of the given register.
     pub fn get_register(&self, reg: &Register) -> u16 {
         let index = reg.to_index() * REGISTER_SIZE;
@@ -71,396 +115,188 @@ impl CPU {
         Ok(buf)
     }
 
-    /// Pushes the given value to the stack, then decrements sp by 1.
-    fn push(&self, value: &[u8]) {
+    /// Pushes the given value to the stack, then decrements sp by 2. Fails without touching the
+    /// stack if it would grow past the start of memory.
+    fn push(&self, value: u16) -> Result<(), CpuError> {
         let sp = self.get_register(&Register::SP);
-        self.memory.set_buf(sp as usize, (sp + 2) as usize, value);
-        self.set_register(&Register::SP, sp - 2);
+        let next_sp = sp.checked_sub(2).ok_or(CpuError::InvalidAddress(sp))?;
+        self.write_u16(sp, value)?;
+        self.set_register(&Register::SP, next_sp);
+        Ok(())
     }
 
-    /// Pops the value from the stack, then increments sp by 1. Returns the popped value.
-    fn pop(&self) -> [u8; REGISTER_SIZE] {
-        let next_sp = self.get_register(&Register::SP) + 2;
+    /// Increments sp by 2, then pops the value from the stack. Returns the popped value, or fails
+    /// without touching sp if the stack is empty.
+    fn pop(&self) -> Result<u16, CpuError> {
+        let sp = self.get_register(&Register::SP);
+        let next_sp = sp.checked_add(2).ok_or(CpuError::InvalidAddress(sp))?;
+        let value = self.read_u16(next_sp)?;
         self.set_register(&Register::SP, next_sp);
-        self.memory
-            .get_buf(next_sp as usize, (next_sp as usize) + 2)
-            .unwrap()
-            .try_into()
-            .unwrap()
+        Ok(value)
+    }
+
+    /// Reads the 16 bit value stored at the given address.
+    fn read_u16(&self, addr: u16) -> Result<u16, CpuError> {
+        let buf = self
+            .memory
+            .get_buf(addr as usize, addr as usize + 2)
+            .ok_or(CpuError::InvalidAddress(addr))?;
+        let value = to_u16(&buf);
+        self.effects.borrow_mut().reads.push((addr, buf));
+        Ok(value)
     }
 
-    fn fetch_reg_idx(&self) -> Result<usize, CpuError> {
-        Ok(((self.fetch()? as usize) % Register::COUNT) * 2)
+    /// Writes the 16 bit value at the given address.
+    fn write_u16(&self, addr: u16, value: u16) -> Result<(), CpuError> {
+        if addr as usize + 2 > self.memory.len() {
+            return Err(CpuError::InvalidAddress(addr));
+        }
+        self.memory
+            .set_buf(addr as usize, addr as usize + 2, &value.to_be_bytes());
+        self.effects
+            .borrow_mut()
+            .writes
+            .push((addr, value.to_be_bytes().to_vec()));
+        Ok(())
     }
 
-    /// Executes the given instruction opcode. Returns true if the halt instruction is reached.
-    /// False otherwise
+    /// Executes the given instruction opcode, fetching its operands as described by the
+    /// instruction table. Returns true if the program halts. False otherwise
     pub fn execute(&self, instruction: OpCode) -> Result<bool, CpuError> {
-        match instruction {
-            OpCode::MovLitReg => {
-                let lit = self.fetch_buf(2)?;
-                let reg = self.fetch_reg_idx()?;
-                self.registers_memory.set_buf(reg, reg + 2, &lit);
-            }
-            OpCode::MovRegReg => {
-                let reg_from = self.fetch_reg_idx()?;
-                let reg_to = self.fetch_reg_idx()?;
-                let val = to_u16(
-                    &self
-                        .registers_memory
-                        .get_buf(reg_from, reg_from + 2)
-                        .unwrap(),
-                );
-                self.registers_memory
-                    .set_buf(reg_to, reg_to + 2, &val.to_be_bytes());
-            }
-            OpCode::MovRegMem => {
-                let reg = self.fetch_reg_idx()?;
-                let addr = to_u16(&self.fetch_buf(2)?);
-                let val = &self.registers_memory.get_buf(reg, reg + 2).unwrap();
-                self.memory.set_buf(addr as usize, (addr as usize) + 2, val);
-            }
-            OpCode::MovMemReg => {
-                let addr = to_u16(&self.fetch_buf(2)?);
-                let reg = self.fetch_reg_idx()?;
-                let val = &self
-                    .memory
-                    .get_buf(addr as usize, (addr as usize) + 2)
-                    .unwrap();
-                self.registers_memory
-                    .set_buf(reg as usize, (reg as usize) + 2, val);
-            }
-            OpCode::MovLitMem => {
-                let val = self.fetch_buf(2)?;
-                let addr = to_u16(&self.fetch_buf(2)?);
-                self.memory
-                    .set_buf(addr as usize, (addr as usize) + 2, &val);
-            }
-            OpCode::MovRegPtrReg => {
-                let reg_from = self.fetch_reg_idx()?;
-                let reg_to = self.fetch_reg_idx()?;
-                let ptr = to_u16(
-                    &self
-                        .registers_memory
-                        .get_buf(reg_from as usize, (reg_from as usize) + 2)
-                        .unwrap(),
-                );
-                let value = &self
-                    .memory
-                    .get_buf(ptr as usize, (ptr as usize) + 2)
-                    .unwrap();
-                self.registers_memory.set_buf(reg_to, reg_to + 2, value);
-            }
-            OpCode::AddRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val1 + reg_val2;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::AddLitReg => {
-                let val = to_u16(&self.fetch_buf(2)?);
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = val + reg_val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::SubRegLit => {
-                let val = to_u16(&self.fetch_buf(2)?);
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = val - reg_val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::SubLitReg => {
-                let val = to_u16(&self.fetch_buf(2)?);
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val - val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::SubRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val2 - reg_val1;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::MulLitReg => {
-                let val = to_u16(&self.fetch_buf(2)?);
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = val * reg_val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::MulRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val1 * reg_val2;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::IncReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val + 1;
-                self.registers_memory
-                    .set_buf(r_idx, r_idx + 2, &result.to_be_bytes());
-            }
-            OpCode::DecReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val - 1;
-                self.registers_memory
-                    .set_buf(r_idx, r_idx + 2, &result.to_be_bytes());
-            }
-            OpCode::ShlRegLit => {
-                let val = to_u16(&self.fetch_buf(2)?);
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val << val;
-                self.registers_memory
-                    .set_buf(r_idx, r_idx + 2, &result.to_be_bytes());
-            }
-            OpCode::ShlRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val1 << reg_val2;
-                self.registers_memory
-                    .set_buf(r1_idx, r1_idx + 2, &result.to_be_bytes());
-            }
-            OpCode::ShrRegLit => {
-                let val = to_u16(&self.fetch_buf(2)?);
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val >> val;
-                self.registers_memory
-                    .set_buf(r_idx, r_idx + 2, &result.to_be_bytes());
-            }
-            OpCode::ShrRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val1 >> reg_val2;
-                self.registers_memory
-                    .set_buf(r1_idx, r1_idx + 2, &result.to_be_bytes());
-            }
-            OpCode::AndRegLit => {
-                let r_idx = self.fetch_reg_idx()?;
-                let val = to_u16(&self.fetch_buf(2)?);
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val & val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::AndRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val1 & reg_val2;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::OrRegLit => {
-                let r_idx = self.fetch_reg_idx()?;
-                let val = to_u16(&self.fetch_buf(2)?);
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val | val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::OrRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val1 | reg_val2;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::XorRegLit => {
-                let r_idx = self.fetch_reg_idx()?;
-                let val = to_u16(&self.fetch_buf(2)?);
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = reg_val ^ val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::XorRegReg => {
-                let r1_idx = self.fetch_reg_idx()?;
-                let r2_idx = self.fetch_reg_idx()?;
-                let reg_val1 = to_u16(&self.registers_memory.get_buf(r1_idx, r1_idx + 2).unwrap());
-                let reg_val2 = to_u16(&self.registers_memory.get_buf(r2_idx, r2_idx + 2).unwrap());
-                let result = reg_val1 ^ reg_val2;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::NotReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let result = !reg_val;
-                self.set_register(&Register::ACC, result);
-            }
-            OpCode::JmpNELit => {
-                let value = to_u16(&self.fetch_buf(2)?);
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if value != self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpNEReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if reg_val != self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpEQLit => {
-                let value = to_u16(&self.fetch_buf(2)?);
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if value == self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpEQReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if reg_val == self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpLTLit => {
-                let value = to_u16(&self.fetch_buf(2)?);
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if value < self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpLTReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if reg_val < self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpGTLit => {
-                let value = to_u16(&self.fetch_buf(2)?);
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if value > self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpGTReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if reg_val > self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpLELit => {
-                let value = to_u16(&self.fetch_buf(2)?);
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if value <= self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpLEReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if reg_val <= self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
-            }
-            OpCode::JmpGELit => {
-                let value = to_u16(&self.fetch_buf(2)?);
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if value >= self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
-                }
+        let def = instruction.def();
+        let operands = def.decode_operands(&self.fetch_buf(def.size() - 1)?)?;
+        // the value of the operand at the given position: the value of a register operand, the
+        // address of a memory operand, the address a relative operand points to, and the literal
+        // itself otherwise. Relative operands count from the end of the instruction
+        let next = self.get_register(&Register::IP);
+        let value = |i: usize| match operands[i] {
+            Operand::Reg(reg) => self.get_register(&reg),
+            Operand::Value(value) if def.operands[i] == OperandKind::Rel => {
+                next.wrapping_add(value)
+            }
+            Operand::Value(value) => value,
+            Operand::Indexed(address) => address.address(|reg| self.get_register(reg)),
+        };
+        // the register of a register operand
+        let reg = |i: usize| match operands[i] {
+            Operand::Reg(reg) => reg,
+            Operand::Value(_) | Operand::Indexed(_) => {
+                unreachable!("operand {} of {:?} is not a register", i, instruction)
             }
-            OpCode::JmpGEReg => {
-                let r_idx = self.fetch_reg_idx()?;
-                let reg_val = to_u16(&self.registers_memory.get_buf(r_idx, r_idx + 2).unwrap());
-                let addr = to_u16(&self.fetch_buf(2)?);
-                if reg_val >= self.get_register(&Register::ACC) {
-                    self.set_register(&Register::IP, addr);
+        };
+        let acc = self.get_register(&Register::ACC);
+
+        use OpCode::*;
+        match instruction {
+            Nop => (),
+            MovLitReg | MovRegReg | MovRelReg => self.set_register(&reg(1), value(0)),
+            MovRegMem | MovLitMem | MovRegRegPtr | MovRegIdx | MovLitIdx => {
+                self.write_u16(value(1), value(0))?
+            }
+            MovMemReg | MovRegPtrReg | MovIdxReg => {
+                self.set_register(&reg(1), self.read_u16(value(0))?)
+            }
+            AddRegReg | AddLitReg => {
+                self.set_register(&Register::ACC, value(0).wrapping_add(value(1)))
+            }
+            // `sub a b` computes b - a
+            SubRegReg | SubLitReg | SubRegLit => {
+                self.set_register(&Register::ACC, value(1).wrapping_sub(value(0)))
+            }
+            MulRegReg | MulLitReg => {
+                self.set_register(&Register::ACC, value(0).wrapping_mul(value(1)))
+            }
+            IncReg => self.set_register(&reg(0), value(0).wrapping_add(1)),
+            DecReg => self.set_register(&reg(0), value(0).wrapping_sub(1)),
+            ShlRegLit | ShlRegReg => {
+                self.set_register(&reg(0), value(0).checked_shl(value(1) as u32).unwrap_or(0))
+            }
+            ShrRegLit | ShrRegReg => {
+                self.set_register(&reg(0), value(0).checked_shr(value(1) as u32).unwrap_or(0))
+            }
+            AndRegLit | AndRegReg => self.set_register(&Register::ACC, value(0) & value(1)),
+            OrRegLit | OrRegReg => self.set_register(&Register::ACC, value(0) | value(1)),
+            XorRegLit | XorRegReg => self.set_register(&Register::ACC, value(0) ^ value(1)),
+            NotReg => self.set_register(&Register::ACC, !value(0)),
+            // conditional jumps compare their second operand against the acc register
+            JmpNELit | JmpNEReg | JmpEQLit | JmpEQReg | JmpLTLit | JmpLTReg | JmpGTLit
+            | JmpGTReg | JmpLELit | JmpLEReg | JmpGELit | JmpGEReg | JmpNELitRel | JmpNERegRel
+            | JmpEQLitRel | JmpEQRegRel | JmpLTLitRel | JmpLTRegRel | JmpGTLitRel | JmpGTRegRel
+            | JmpLELitRel | JmpLERegRel | JmpGELitRel | JmpGERegRel => {
+                let taken = match instruction {
+                    JmpNELit | JmpNEReg | JmpNELitRel | JmpNERegRel => value(1) != acc,
+                    JmpEQLit | JmpEQReg | JmpEQLitRel | JmpEQRegRel => value(1) == acc,
+                    JmpLTLit | JmpLTReg | JmpLTLitRel | JmpLTRegRel => value(1) < acc,
+                    JmpGTLit | JmpGTReg | JmpGTLitRel | JmpGTRegRel => value(1) > acc,
+                    JmpLELit | JmpLEReg | JmpLELitRel | JmpLERegRel => value(1) <= acc,
+                    _ => value(1) >= acc,
+                };
+                if taken {
+                    self.set_register(&Register::IP, value(0));
                 }
             }
-            OpCode::Jmp => {
-                let addr = to_u16(&self.fetch_buf(2)?);
-                self.set_register(&Register::IP, addr);
-            }
-            OpCode::PshLit => {
-                let value = &self.fetch_buf(2)?;
-                self.push(value)
-            }
-            OpCode::PshReg => {
-                let reg = self.fetch_reg_idx()?;
-                let value = &self.registers_memory.get_buf(reg, reg + 2).unwrap();
-                self.push(value)
+            Jmp | JmpReg | JmpRel => self.set_register(&Register::IP, value(0)),
+            JmpMem | JmpRegPtr | JmpIdx => {
+                self.set_register(&Register::IP, self.read_u16(value(0))?)
             }
-            OpCode::Pop => {
-                let reg_idx = self.fetch_reg_idx()?;
-                self.registers_memory
-                    .set_buf(reg_idx, reg_idx + 2, &self.pop());
+            PshLit | PshReg => self.push(value(0))?,
+            Pop => self.set_register(&reg(0), self.pop()?),
+            CalLit | CalReg | CalRel => {
+                self.push(self.get_register(&Register::IP))?;
+                self.set_register(&Register::IP, value(0));
             }
-            OpCode::CalLit => {
-                let addr = to_u16(&self.fetch_buf(2)?);
-                self.push(&self.get_register(&Register::IP).to_be_bytes());
+            Ret => {
+                let addr = self.pop()?;
                 self.set_register(&Register::IP, addr);
             }
-            OpCode::CalReg => {
-                let reg_idx = self.fetch_reg_idx()?;
-                let addr = to_u16(&self.registers_memory.get_buf(reg_idx, reg_idx + 2).unwrap());
-                self.push(&self.get_register(&Register::IP).to_be_bytes());
-                self.set_register(&Register::IP, addr);
-            }
-            OpCode::Ret => {
-                let addr = to_u16(&self.pop());
-                self.set_register(&Register::IP, addr);
-            }
-            OpCode::Hlt => {
+            // the program exits with the value of the acc register
+            Hlt => {
+                self.exit(acc);
                 return Ok(true);
             }
-            OpCode::SysLit => {
-                let value = self.fetch()?;
-                self.syscall(value)?;
+            SysLit => {
+                self.exit_status.set(None);
+                self.syscall(value(0) as u8)?;
+                return Ok(self.exit_status.get().is_some());
             }
-            OpCode::Nop => (),
         }
         Ok(false)
     }
 
     fn syscall(&self, value: u8) -> Result<(), CpuError> {
-        match value {
-            // prints the value of the accumulator
-            0x00 => {
-                println!("{}", self.get_register(&Register::ACC));
-            }
-            _ => return Err(CpuError::InvalidSyscall(value)),
-        };
-        Ok(())
+        self.effects.borrow_mut().syscall = Some(value);
+        match self.syscalls.by_number(value) {
+            Some(syscall) => (syscall.handler)(self),
+            None => Err(CpuError::InvalidSyscall(value)),
+        }
     }
 
+    /// Fetches and executes the instruction at ip. Returns true if the program halts.
     pub fn step(&self) -> Result<bool, CpuError> {
+        self.effects.replace(Effects::default());
         let instruction = self.fetch()?;
-        self.execute(OpCode::from(instruction))
+        self.execute(OpCode::try_from(instruction)?)
     }
 
-    pub fn run(&self) -> Result<(), CpuError> {
-        for line in std::io::stdin().lock().lines() {
-            if self.step()? {
-                break;
-            }
-            println!("{}", self);
-        }
-        Ok(())
+    /// Gets how the last instruction stepped through used memory, and the syscall it called.
+    pub fn effects(&self) -> Effects {
+        self.effects.borrow().clone()
+    }
+
+    /// Halts the program with the given exit status, once the current instruction is executed.
+    pub fn exit(&self, status: u16) {
+        self.exit_status.set(Some(status));
+    }
+
+    /// Gets the exit status of the program if it has halted, either with `hlt` or by exiting.
+    pub fn exit_status(&self) -> Option<u16> {
+        self.exit_status.get()
+    }
+
+    /// Runs until the program halts, returning its exit status.
+    pub fn run(&self) -> Result<u16, CpuError> {
+        while !self.step()? {}
+        Ok(self.exit_status().unwrap_or_default())
     }
 }
 
@@ -0,0 +1,502 @@
+use std::ops::Range;
+
+use strum::IntoEnumIterator;
+
+use crate::{
+    cpu::{CpuError, CPU},
+    disassembler::{Disassembled, Disassembler},
+    image::Image,
+    opcodes::{OpCode, INSTRUCTIONS},
+    register::Register,
+};
+
+/// The instructions shown before and after the one at ip by `disassemble`.
+const CONTEXT: usize = 4;
+
+/// The bytes shown by `x` without a count.
+const EXAMINE_BYTES: usize = 16;
+
+/// The bytes shown on each line by `x`.
+const BYTES_PER_LINE: usize = 8;
+
+const HELP: &str = "\
+break [<addr>]       Stop before the instruction at <addr>, or list the breakpoints
+delete [<n>]         Delete breakpoint <n>, or every breakpoint
+step [<n>]           Execute <n> instructions, 1 by default
+next [<n>]           Like step, but run called subroutines until they return
+finish               Run until the current subroutine returns
+continue             Run until a breakpoint is reached or the program halts
+registers            Show the value of every register
+x[/<n>] <addr>       Show <n> bytes of memory starting at <addr>, 16 by default
+set <reg> <value>    Set a register
+set [<addr>] <value> Set the 16 bit word at <addr>
+disassemble          Show the instructions around ip
+help                 Show this help
+quit                 Leave the debugger
+
+Addresses and values are numbers, labels or registers. An empty line repeats the last command.";
+
+/// What the debugger answers to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Text to show, possibly empty
+    Output(String),
+    /// The session is over
+    Quit,
+}
+
+/// Why the program stopped running.
+enum Stop {
+    /// The command is done, such as after the requested number of steps
+    Done,
+    Breakpoint(usize),
+    Halted(u16),
+}
+
+#[derive(Debug)]
+pub enum DebuggerError {
+    UnknownCommand(String),
+    /// The command was given the wrong arguments, the message says which it takes
+    Usage(&'static str),
+    InvalidValue(String),
+    NoBreakpoint(usize),
+    /// The program has halted, so it can't run any further
+    Halted(u16),
+    Cpu(CpuError),
+}
+
+impl std::error::Error for DebuggerError {}
+
+impl std::fmt::Display for DebuggerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DebuggerError::UnknownCommand(command) => {
+                write!(f, "Unknown command: {}, try `help`", command)
+            }
+            DebuggerError::Usage(usage) => write!(f, "Usage: {}", usage),
+            DebuggerError::InvalidValue(value) => {
+                write!(f, "Not a number, label or register: {}", value)
+            }
+            DebuggerError::NoBreakpoint(id) => write!(f, "No breakpoint number {}", id),
+            DebuggerError::Halted(status) => {
+                write!(f, "The program has exited with status {}", status)
+            }
+            DebuggerError::Cpu(e) => write!(f, "The program failed: {}", e),
+        }
+    }
+}
+
+impl From<CpuError> for DebuggerError {
+    fn from(e: CpuError) -> Self {
+        DebuggerError::Cpu(e)
+    }
+}
+
+/// An interactive debugger, running a program loaded into a CPU one command at a time.
+/// Commands are given as text, the way they're typed at the prompt, see [`Debugger::execute`].
+pub struct Debugger<'a> {
+    cpu: &'a CPU,
+    /// The parts of memory holding the program, where instructions are disassembled from
+    segments: Vec<Range<usize>>,
+    /// The labels of the program with their addresses, in definition order
+    symbols: Vec<(String, u16)>,
+    /// The breakpoints set, by number and address
+    breakpoints: Vec<(usize, u16)>,
+    next_breakpoint: usize,
+    /// The command an empty line repeats
+    last_command: String,
+}
+
+impl<'a> Debugger<'a> {
+    /// Creates a debugger for the image, which must already be loaded into the CPU.
+    pub fn new(cpu: &'a CPU, image: &Image) -> Debugger<'a> {
+        Debugger {
+            cpu,
+            segments: image
+                .segments
+                .iter()
+                .map(|segment| segment.addr as usize..segment.addr as usize + segment.data.len())
+                .collect(),
+            symbols: vec![],
+            breakpoints: vec![],
+            next_breakpoint: 1,
+            last_command: String::new(),
+        }
+    }
+
+    /// Lets breakpoints and addresses be given as labels, and names addresses after them. See
+    /// [`Assembly::labels`].
+    ///
+    /// [`Assembly::labels`]: crate::assembler::Assembly::labels
+    pub fn with_symbols<'s>(
+        mut self,
+        symbols: impl IntoIterator<Item = (&'s String, &'s u16)>,
+    ) -> Debugger<'a> {
+        self.symbols = symbols
+            .into_iter()
+            .map(|(name, addr)| (name.clone(), *addr))
+            .collect();
+        self
+    }
+
+    /// Executes a command, as typed at the prompt. Fails if the command is malformed, or if the
+    /// program fails while it runs.
+    pub fn execute(&mut self, line: &str) -> Result<Reply, DebuggerError> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let output = match (command, args.as_slice()) {
+            ("", _) => String::new(),
+            ("break" | "b", []) => self.list_breakpoints(),
+            ("break" | "b", [addr]) => self.add_breakpoint(addr)?,
+            ("break" | "b", _) => return Err(DebuggerError::Usage("break [<addr>]")),
+            ("delete" | "d", []) => {
+                self.breakpoints.clear();
+                "Deleted every breakpoint".to_string()
+            }
+            ("delete" | "d", [id]) => self.delete_breakpoint(id)?,
+            ("delete" | "d", _) => return Err(DebuggerError::Usage("delete [<n>]")),
+            ("step" | "s", _) => {
+                let count = self.count(&args, "step [<n>]")?;
+                self.repeat(count, |debugger| debugger.resume(|_, _| true))?
+            }
+            ("next" | "n", _) => {
+                let count = self.count(&args, "next [<n>]")?;
+                self.repeat(count, Self::next)?
+            }
+            ("finish", []) => {
+                let sp = self.cpu.get_register(&Register::SP);
+                // the stack grows down, so returning from the subroutine takes sp above where
+                // it is now, while returning from those it calls doesn't
+                self.report(self.resume(|debugger, executed| {
+                    executed == OpCode::Ret && debugger.cpu.get_register(&Register::SP) > sp
+                })?)
+            }
+            ("continue" | "c", []) => self.report(self.resume(|_, _| false)?),
+            ("registers" | "regs", []) => self.registers(),
+            ("x", [addr]) => self.examine(EXAMINE_BYTES, addr)?,
+            ("set", [target, value]) => self.set(target, value)?,
+            ("set", _) => {
+                return Err(DebuggerError::Usage(
+                    "set <reg> <value> | set [<addr>] <value>",
+                ))
+            }
+            ("disassemble" | "disas", []) => self.disassemble(),
+            ("help" | "h", []) => HELP.to_string(),
+            ("quit" | "q", []) => return Ok(Reply::Quit),
+            (command, [addr]) if command.starts_with("x/") => {
+                // there's no more to show than the whole memory
+                let count = command[2..]
+                    .parse()
+                    .ok()
+                    .filter(|count| *count <= self.cpu.memory().len())
+                    .ok_or(DebuggerError::Usage("x[/<n>] <addr>"))?;
+                self.examine(count, addr)?
+            }
+            (
+                "finish" | "continue" | "c" | "registers" | "regs" | "disassemble" | "disas"
+                | "help" | "h" | "quit" | "q",
+                _,
+            ) => return Err(DebuggerError::Usage("the command takes no arguments")),
+            ("x", _) => return Err(DebuggerError::Usage("x[/<n>] <addr>")),
+            (command, _) => return Err(DebuggerError::UnknownCommand(command.to_string())),
+        };
+        Ok(Reply::Output(output))
+    }
+
+    /// Describes where the program is: the instruction at ip, or how it exited.
+    pub fn location(&self) -> String {
+        match self.cpu.exit_status() {
+            Some(status) => format!("The program exited with status {}", status),
+            None => self.instruction(self.cpu.get_register(&Register::IP)),
+        }
+    }
+
+    /// Executes instructions until `done` holds after one, given the opcode it executed, a
+    /// breakpoint is reached or the program halts. At least one instruction is executed, so
+    /// that the program can be resumed from a breakpoint.
+    fn resume(&self, done: impl Fn(&Self, OpCode) -> bool) -> Result<Stop, DebuggerError> {
+        if let Some(status) = self.cpu.exit_status() {
+            return Err(DebuggerError::Halted(status));
+        }
+        loop {
+            let executed = self.current()?;
+            if self.cpu.step()? {
+                return Ok(Stop::Halted(self.cpu.exit_status().unwrap_or_default()));
+            }
+            if done(self, executed) {
+                return Ok(Stop::Done);
+            }
+            let ip = self.cpu.get_register(&Register::IP);
+            if let Some((id, _)) = self.breakpoints.iter().find(|(_, addr)| *addr == ip) {
+                return Ok(Stop::Breakpoint(*id));
+            }
+        }
+    }
+
+    /// Executes one instruction, running a called subroutine until it returns.
+    fn next(&self) -> Result<Stop, DebuggerError> {
+        let opcode = self.current()?;
+        let ret = self
+            .cpu
+            .get_register(&Register::IP)
+            .checked_add(opcode.def().size() as u16);
+        // a call ending at the top of memory has nowhere to return to, so it's stepped into
+        let ret = match ret {
+            Some(ret) if matches!(opcode, OpCode::CalLit | OpCode::CalReg | OpCode::CalRel) => ret,
+            _ => return self.resume(|_, _| true),
+        };
+        let sp = self.cpu.get_register(&Register::SP);
+        // a recursive call comes back to the same address deeper in the stack
+        self.resume(|debugger, _| {
+            debugger.cpu.get_register(&Register::IP) == ret
+                && debugger.cpu.get_register(&Register::SP) >= sp
+        })
+    }
+
+    /// Resumes the program `count` times, unless it stops on the way, then says where it is.
+    fn repeat(
+        &self,
+        count: usize,
+        resume: impl Fn(&Self) -> Result<Stop, DebuggerError>,
+    ) -> Result<String, DebuggerError> {
+        let mut stop = Stop::Done;
+        for _ in 0..count {
+            stop = resume(self)?;
+            if !matches!(stop, Stop::Done) {
+                break;
+            }
+        }
+        Ok(self.report(stop))
+    }
+
+    fn report(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Done => self.location(),
+            Stop::Breakpoint(id) => format!("Breakpoint {}, {}", id, self.location()),
+            Stop::Halted(status) => format!("The program exited with status {}", status),
+        }
+    }
+
+    /// Gets the opcode of the instruction at ip.
+    fn current(&self) -> Result<OpCode, DebuggerError> {
+        let ip = self.cpu.get_register(&Register::IP);
+        let byte = self
+            .cpu
+            .memory()
+            .get(ip as usize)
+            .ok_or(CpuError::InvalidAddress(ip))?;
+        Ok(OpCode::try_from(byte)?)
+    }
+
+    fn count(&self, args: &[&str], usage: &'static str) -> Result<usize, DebuggerError> {
+        match args {
+            [] => Ok(1),
+            [count] => count.parse().map_err(|_| DebuggerError::Usage(usage)),
+            _ => Err(DebuggerError::Usage(usage)),
+        }
+    }
+
+    fn add_breakpoint(&mut self, addr: &str) -> Result<String, DebuggerError> {
+        let addr = self.value(addr)?;
+        let id = self.next_breakpoint;
+        self.next_breakpoint += 1;
+        self.breakpoints.push((id, addr));
+        Ok(format!("Breakpoint {} at {}", id, self.name(addr)))
+    }
+
+    fn delete_breakpoint(&mut self, id: &str) -> Result<String, DebuggerError> {
+        let id: usize = id
+            .parse()
+            .map_err(|_| DebuggerError::Usage("delete [<n>]"))?;
+        let at = self
+            .breakpoints
+            .iter()
+            .position(|(n, _)| *n == id)
+            .ok_or(DebuggerError::NoBreakpoint(id))?;
+        self.breakpoints.remove(at);
+        Ok(format!("Deleted breakpoint {}", id))
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints".to_string();
+        }
+        let lines: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|(id, addr)| format!("{:<4}{}", id, self.name(*addr)))
+            .collect();
+        lines.join("\n")
+    }
+
+    fn registers(&self) -> String {
+        let lines: Vec<String> = Register::iter()
+            .map(|reg| {
+                let value = self.cpu.get_register(&reg);
+                format!(
+                    "{:<4}0x{:04X}  {}",
+                    reg.as_ref().to_lowercase(),
+                    value,
+                    value
+                )
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    fn examine(&self, count: usize, addr: &str) -> Result<String, DebuggerError> {
+        let start = self.value(addr)? as usize;
+        let end = (start + count).min(self.cpu.memory().len());
+        let bytes = self.cpu.memory().get_buf(start, end).unwrap_or_default();
+        let lines: Vec<String> = bytes
+            .chunks(BYTES_PER_LINE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                format!("0x{:04X}: {}", start + i * BYTES_PER_LINE, hex.join(" "))
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn set(&self, target: &str, value: &str) -> Result<String, DebuggerError> {
+        let value = self.value(value)?;
+        match target.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            Some(addr) => {
+                let addr = self.value(addr)?;
+                if addr as usize + 2 > self.cpu.memory().len() {
+                    return Err(CpuError::InvalidAddress(addr).into());
+                }
+                self.cpu
+                    .memory()
+                    .set_buf(addr as usize, addr as usize + 2, &value.to_be_bytes());
+                Ok(format!("[{}] = 0x{:04X}", self.name(addr), value))
+            }
+            None => {
+                let reg: Register = target
+                    .parse()
+                    .map_err(|_| DebuggerError::InvalidValue(target.to_string()))?;
+                self.cpu.set_register(&reg, value);
+                Ok(format!("{} = 0x{:04X}", target.to_lowercase(), value))
+            }
+        }
+    }
+
+    /// Shows the instructions before and after ip, disassembled from the start of the segment
+    /// holding it so that they're decoded the same way they're executed.
+    fn disassemble(&self) -> String {
+        let ip = self.cpu.get_register(&Register::IP);
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.contains(&(ip as usize)))
+            .cloned()
+            .unwrap_or_else(|| self.following(ip, CONTEXT + 1));
+        let lines = self.instructions(segment);
+        let at = match lines.iter().position(|line| line.addr >= ip) {
+            // ip is in the middle of what was decoded, so start decoding at ip instead
+            Some(at) if lines[at].addr != ip => {
+                return self.show(&self.instructions(self.following(ip, CONTEXT + 1)), 0, ip);
+            }
+            Some(at) => at,
+            None => return self.location(),
+        };
+        self.show(&lines, at.saturating_sub(CONTEXT), ip)
+    }
+
+    fn show(&self, lines: &[Disassembled], from: usize, ip: u16) -> String {
+        let shown: Vec<String> = lines[from..]
+            .iter()
+            .take(CONTEXT * 2 + 1)
+            .map(|line| {
+                let marker = if line.addr == ip { "=>" } else { "  " };
+                format!("{} {}", marker, self.line(line))
+            })
+            .collect();
+        shown.join("\n")
+    }
+
+    /// Disassembles the range, keeping only instructions and data.
+    fn instructions(&self, range: Range<usize>) -> Vec<Disassembled> {
+        let disassembler = Disassembler::new(self.cpu.memory())
+            .with_syscalls(self.cpu.syscalls().clone())
+            .with_symbols(self.symbols.iter().map(|(name, addr)| (name, addr)));
+        disassembler
+            .disassemble(range)
+            .into_iter()
+            .filter(|line| !line.bytes.is_empty())
+            .collect()
+    }
+
+    /// Gets the memory that the given number of instructions starting at the address fit in.
+    fn following(&self, addr: u16, count: usize) -> Range<usize> {
+        let longest = INSTRUCTIONS.iter().map(|def| def.size()).max().unwrap_or(1);
+        let end = (addr as usize + count * longest).min(self.cpu.memory().len());
+        addr as usize..end
+    }
+
+    /// Describes the instruction at the address.
+    fn instruction(&self, addr: u16) -> String {
+        match self.instructions(self.following(addr, 1)).first() {
+            Some(line) => self.line(line),
+            None => self.name(addr),
+        }
+    }
+
+    fn line(&self, line: &Disassembled) -> String {
+        format!("{}: {}", self.name(line.addr), line.node)
+    }
+
+    /// Names an address after the closest label at or before it in the same segment, as in
+    /// `0x0012 <main+4>`.
+    fn name(&self, addr: u16) -> String {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.contains(&(addr as usize)));
+        let closest = self
+            .symbols
+            .iter()
+            .filter(|(_, at)| *at == addr || segment.is_some_and(|s| s.contains(&(*at as usize))))
+            .filter(|(_, at)| *at <= addr)
+            .max_by_key(|(_, at)| *at);
+        match closest {
+            Some((name, at)) if *at == addr => format!("0x{:04X} <{}>", addr, name),
+            Some((name, at)) => format!("0x{:04X} <{}+{}>", addr, name, addr - at),
+            None => format!("0x{:04X}", addr),
+        }
+    }
+
+    /// Parses a number, in the notations of the assembler, a label or a register.
+    fn value(&self, text: &str) -> Result<u16, DebuggerError> {
+        parse_number(text)
+            .or_else(|| {
+                self.symbols
+                    .iter()
+                    .find(|(name, _)| name == text)
+                    .map(|(_, addr)| *addr)
+            })
+            .or_else(|| {
+                let reg: Register = text.parse().ok()?;
+                Some(self.cpu.get_register(&reg))
+            })
+            .ok_or_else(|| DebuggerError::InvalidValue(text.to_string()))
+    }
+}
+
+/// Parses a number written as the assembler takes them: decimal, or hexadecimal, binary or octal
+/// with the `0x`, `0b` and `0o` prefixes.
+pub(crate) fn parse_number(text: &str) -> Option<u16> {
+    match text.get(..2) {
+        Some("0x") => u16::from_str_radix(&text[2..], 16).ok(),
+        Some("0b") => u16::from_str_radix(&text[2..], 2).ok(),
+        Some("0o") => u16::from_str_radix(&text[2..], 8).ok(),
+        _ => text.parse().ok(),
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{assembler::AssemblerError, ast::SourceLoc};
+
+/// How serious a diagnostic is. Any error makes the run fail, warnings and notes are only
+/// reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Information about what was done to the program, such as the changes of the optimizer
+    Note,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Note => write!(f, "note"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A single message about the program, optionally pointing at the code it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub loc: Option<SourceLoc>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: String, loc: Option<SourceLoc>) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message,
+            loc,
+        }
+    }
+}
+
+impl From<AssemblerError> for Diagnostic {
+    fn from(error: AssemblerError) -> Self {
+        match error {
+            AssemblerError::Located(loc, e) => {
+                Diagnostic::new(Severity::Error, e.to_string(), Some(loc))
+            }
+            AssemblerError::IncludeNotFound(path, loc) => Diagnostic::new(
+                Severity::Error,
+                format!("Include not found: {}", path),
+                Some(loc),
+            ),
+            AssemblerError::RecursiveInclude(chain, loc) => Diagnostic::new(
+                Severity::Error,
+                format!("Recursive include: {}", chain.join(" -> ")),
+                Some(loc),
+            ),
+            e => Diagnostic::new(Severity::Error, e.to_string(), None),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(loc) = &self.loc {
+            write!(f, "{}: ", loc)?;
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Finds the candidate closest to a misspelled name, for "did you mean" hints. Candidates more
+/// than one edit away, or two for names longer than three characters, aren't suggested.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let name = name.to_lowercase();
+    let limit = if name.chars().count() > 3 { 2 } else { 1 };
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(&name, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Formats the hint for a suggestion of [`suggest`], to be appended to an error message.
+pub fn did_you_mean(suggestion: Option<&str>) -> String {
+    suggestion.map_or(String::new(), |suggestion| {
+        format!(", did you mean `{}`?", suggestion)
+    })
+}
+
+/// Counts the insertions, deletions, substitutions and swaps of adjacent characters needed to
+/// turn one string into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    // distances[i][j] is the distance between the first i characters of a and j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// Collects the diagnostics produced during a run, so that every problem can be reported at once
+/// instead of stopping at the first one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    items: Vec<Diagnostic>,
+    werror: bool,
+}
+
+impl Diagnostics {
+    /// Creates an empty collector. With `werror`, warnings are reported as errors.
+    pub fn new(werror: bool) -> Diagnostics {
+        Diagnostics {
+            items: vec![],
+            werror,
+        }
+    }
+
+    pub fn push(&mut self, mut diagnostic: Diagnostic) {
+        if self.werror && diagnostic.severity == Severity::Warning {
+            diagnostic.severity = Severity::Error;
+        }
+        self.items.push(diagnostic);
+    }
+
+    /// Sorts the diagnostics by the given key, keeping the order they were found in for equal
+    /// keys.
+    pub fn sort_by_key<K: Ord>(&mut self, key: impl FnMut(&Diagnostic) -> K) {
+        self.items.sort_by_key(key);
+    }
+
+    pub fn error(&mut self, message: String, loc: Option<SourceLoc>) {
+        self.push(Diagnostic::new(Severity::Error, message, loc));
+    }
+
+    pub fn warn(&mut self, message: String, loc: Option<SourceLoc>) {
+        self.push(Diagnostic::new(Severity::Warning, message, loc));
+    }
+
+    pub fn note(&mut self, message: String, loc: Option<SourceLoc>) {
+        self.push(Diagnostic::new(Severity::Note, message, loc));
+    }
+
+    /// Determines if any of the collected diagnostics is an error.
+    pub fn has_errors(&self) -> bool {
+        self.count(Severity::Error) > 0
+    }
+
+    /// Counts the collected diagnostics of the given severity.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.items.iter().filter(|d| d.severity == severity).count()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl Extend<Diagnostic> for Diagnostics {
+    fn extend<T: IntoIterator<Item = Diagnostic>>(&mut self, iter: T) {
+        for diagnostic in iter {
+            self.push(diagnostic);
+        }
+    }
+}
+
+impl IntoIterator for Diagnostics {
+    type Item = Diagnostic;
+    type IntoIter = std::vec::IntoIter<Diagnostic>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for diagnostic in &self.items {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    ast::{ASTArg, ASTNode},
+    image::Image,
+    memory::Memory,
+    opcodes::{OpCode, OperandKind},
+    syscalls::SyscallRegistry,
+};
+
+/// The most bytes put in a single `.byte` directive.
+const BYTES_PER_LINE: usize = 8;
+
+/// Turns memory back into source. Every address holding a known opcode followed by valid
+/// operands is decoded as an instruction, anything else is shown as `.byte` data.
+pub struct Disassembler<'a> {
+    memory: &'a Memory,
+    /// The names of the labels at each address, sorted
+    symbols: HashMap<u16, Vec<String>>,
+    /// Used to name the syscalls called by `sys`
+    syscalls: SyscallRegistry,
+}
+
+/// A single line of disassembly, along with where it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembled {
+    /// The address the line starts at
+    pub addr: u16,
+    /// The bytes the line was decoded from, empty for labels and directives
+    pub bytes: Vec<u8>,
+    pub node: ASTNode,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(memory: &'a Memory) -> Disassembler<'a> {
+        Disassembler {
+            memory,
+            symbols: HashMap::new(),
+            syscalls: SyscallRegistry::default(),
+        }
+    }
+
+    /// Names the syscalls of the given registry instead of the builtin ones.
+    pub fn with_syscalls(mut self, syscalls: SyscallRegistry) -> Disassembler<'a> {
+        self.syscalls = syscalls;
+        self
+    }
+
+    /// Uses the given symbols, pairs of label names and addresses, to name the addresses of
+    /// jumps, calls and memory operands, and to put labels back in place. Labels sharing an
+    /// address are put back in the order given, see [`Assembly::labels`].
+    ///
+    /// [`Assembly::labels`]: crate::assembler::Assembly::labels
+    pub fn with_symbols<'s>(
+        mut self,
+        symbols: impl IntoIterator<Item = (&'s String, &'s u16)>,
+    ) -> Disassembler<'a> {
+        for (name, addr) in symbols {
+            self.symbols
+                .entry(*addr)
+                .or_default()
+                .push(symbol_name(name));
+        }
+        self
+    }
+
+    /// Disassembles the given range of memory. A range that doesn't start at address 0 is
+    /// preceded by an `.org` directive, so that the output assembles back to the same bytes.
+    pub fn disassemble(&self, range: Range<usize>) -> Vec<Disassembled> {
+        let end = range.end.min(self.memory.len());
+        let mut lines = vec![];
+        if range.start != 0 {
+            lines.push(Disassembled {
+                addr: range.start as u16,
+                bytes: vec![],
+                node: ASTNode::Org(ASTArg::Lit(range.start as u16)),
+            });
+        }
+
+        let mut addr = range.start;
+        // the data bytes not yet put in a `.byte` line, and where they start
+        let mut data: Vec<u8> = vec![];
+        let mut data_start = addr;
+        while addr < end {
+            let labels = self.symbols.get(&(addr as u16));
+            if labels.is_some() || data.len() == BYTES_PER_LINE {
+                Self::flush_data(&mut lines, &mut data, data_start);
+            }
+            for name in labels.into_iter().flatten() {
+                lines.push(Disassembled {
+                    addr: addr as u16,
+                    bytes: vec![],
+                    node: ASTNode::Label(name.clone()),
+                });
+            }
+
+            match self.decode(addr, end) {
+                Some((node, size)) => {
+                    Self::flush_data(&mut lines, &mut data, data_start);
+                    lines.push(Disassembled {
+                        addr: addr as u16,
+                        bytes: self.memory.get_buf(addr, addr + size).unwrap(),
+                        node,
+                    });
+                    addr += size;
+                }
+                None => {
+                    if data.is_empty() {
+                        data_start = addr;
+                    }
+                    data.push(self.memory.get(addr).unwrap());
+                    addr += 1;
+                }
+            }
+        }
+        Self::flush_data(&mut lines, &mut data, data_start);
+        lines
+    }
+
+    /// Disassembles every segment of the image, loaded into this disassembler's memory. The
+    /// entry point is kept with an `.entry` directive when it isn't the start of the first
+    /// segment.
+    pub fn disassemble_image(&self, image: &Image) -> Vec<Disassembled> {
+        let mut lines = vec![];
+        let default_entry = image
+            .segments
+            .iter()
+            .find(|segment| !segment.data.is_empty())
+            .map_or(0, |segment| segment.addr);
+        if image.entry != default_entry {
+            lines.push(Disassembled {
+                addr: image.entry,
+                bytes: vec![],
+                node: ASTNode::Entry(self.address(image.entry)),
+            });
+        }
+        for segment in &image.segments {
+            let start = segment.addr as usize;
+            lines.extend(self.disassemble(start..start + segment.data.len()));
+        }
+        lines
+    }
+
+    /// Renders disassembled lines as canonical source text, with instructions and data indented
+    /// under labels.
+    pub fn to_source(lines: &[Disassembled]) -> String {
+        let mut source = String::new();
+        for line in lines {
+            match &line.node {
+                ASTNode::Label(_) | ASTNode::Org(_) | ASTNode::Entry(_) => {}
+                _ => source.push_str("  "),
+            }
+            source.push_str(&line.node.to_string());
+            source.push('\n');
+        }
+        source
+    }
+
+    /// Decodes the instruction at `addr`, if there's a valid one that ends before `end` and
+    /// doesn't cover the address of any label. Returns it with its size.
+    fn decode(&self, addr: usize, end: usize) -> Option<(ASTNode, usize)> {
+        let def = OpCode::try_from(self.memory.get(addr)?).ok()?.def();
+        let size = def.size();
+        if addr + size > end
+            || (addr + 1..addr + size).any(|a| self.symbols.contains_key(&(a as u16)))
+        {
+            return None;
+        }
+        let bytes = self.memory.get_buf(addr + 1, addr + size)?;
+        let operands = def.decode_operands(&bytes).ok()?;
+        let args = def
+            .operands
+            .iter()
+            .zip(operands)
+            .map(|(kind, operand)| match (kind, kind.to_arg(operand)) {
+                (OperandKind::Addr, ASTArg::Lit(value)) => self.address(value),
+                (OperandKind::Byte, ASTArg::Lit(number)) if def.opcode == OpCode::SysLit => {
+                    match self.syscalls.by_number(number as u8) {
+                        Some(syscall) => ASTArg::Label(syscall.name.to_string()),
+                        None => ASTArg::Lit(number),
+                    }
+                }
+                // relative operands count from the end of the instruction, and are written from
+                // its start so that they assemble back to the same relative encoding
+                (OperandKind::Rel, ASTArg::Lit(offset)) => {
+                    ASTArg::Rel(offset as i16 as i32 + size as i32)
+                }
+                (OperandKind::Mem, ASTArg::Mem(inner)) => match *inner {
+                    ASTArg::Lit(value) => ASTArg::Mem(Box::new(self.address(value))),
+                    inner => ASTArg::Mem(Box::new(inner)),
+                },
+                (_, arg) => arg,
+            })
+            .collect();
+        Some((ASTNode::from_instruction(def.mnemonic, args)?, size))
+    }
+
+    /// Names the address by the first label at it, if there's any.
+    fn address(&self, addr: u16) -> ASTArg {
+        match self.symbols.get(&addr).and_then(|names| names.first()) {
+            Some(name) => ASTArg::Label(name.clone()),
+            None => ASTArg::Lit(addr),
+        }
+    }
+
+    fn flush_data(lines: &mut Vec<Disassembled>, data: &mut Vec<u8>, start: usize) {
+        if data.is_empty() {
+            return;
+        }
+        lines.push(Disassembled {
+            addr: start as u16,
+            bytes: data.clone(),
+            node: ASTNode::Byte(data.drain(..).map(|b| ASTArg::Lit(b as u16)).collect()),
+        });
+    }
+}
+
+/// Turns a qualified label name into one that can be written in source. Numeric labels such as
+/// `main.1@4` become `main.1_4`, and local labels outside of any global label lose their dot, as
+/// they would otherwise be qualified again.
+fn symbol_name(label: &str) -> String {
+    label.trim_start_matches('.').replace('@', "_")
+}
@@ -0,0 +1,196 @@
+use std::rc::Rc;
+
+use crate::{
+    assembler::{is_numeric_label, AssemblerError},
+    ast::{ASTNode, Statement},
+    parser::ASTParser,
+};
+
+/// The indentation of each level of nesting under labels.
+const INDENT: &str = "  ";
+
+/// Reprints programs in canonical form: lowercase mnemonics and registers, hexadecimal numbers,
+/// code indented under the label it belongs to and the operands of consecutive instructions
+/// aligned in columns. Comments are kept where they are, and runs of blank lines are collapsed
+/// into one.
+pub struct Formatter;
+
+/// A line of formatted output, before alignment.
+enum Line {
+    Blank,
+    /// A line printed as is, at the given depth, with its trailing comment
+    Text(usize, String, Option<String>),
+    /// An instruction at the given depth, with its mnemonic, operands and trailing comment
+    Instruction(usize, &'static str, Vec<String>, Option<String>),
+}
+
+impl Line {
+    /// Attaches a trailing comment to the line, if it can have one and doesn't already.
+    fn attach(&mut self, text: &str) -> bool {
+        match self {
+            Line::Text(.., comment @ None) | Line::Instruction(.., comment @ None) => {
+                *comment = Some(text.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Formatter {
+    /// Parses and formats the source, without resolving its includes. `name` is the file name
+    /// used in parse errors.
+    pub fn format_source(source: &str, name: &str) -> Result<String, AssemblerError> {
+        Ok(Self::format(&ASTParser::parse_source(
+            source,
+            Rc::from(name),
+        )?))
+    }
+
+    /// Formats the statements of a single source file.
+    pub fn format(statements: &[Statement]) -> String {
+        // the depth of every statement, comments on their own line taking the depth of the
+        // statement they precede
+        let mut depths = Vec::with_capacity(statements.len());
+        // whether a global label was seen, and the depth of the code following the last label
+        let mut in_global = false;
+        let mut depth = 0;
+        for stmt in statements {
+            depths.push(match &stmt.node {
+                ASTNode::Label(name) if is_global(name) => {
+                    in_global = true;
+                    depth = 1;
+                    Some(0)
+                }
+                ASTNode::Label(_) => {
+                    depth = in_global as usize + 1;
+                    Some(depth - 1)
+                }
+                ASTNode::Include(_) | ASTNode::Org(_) | ASTNode::Entry(_) => Some(0),
+                ASTNode::Comment(_) => None,
+                _ => Some(depth),
+            });
+        }
+        let mut next = depth;
+        for depth in depths.iter_mut().rev() {
+            next = *depth.get_or_insert(next);
+        }
+
+        let mut lines: Vec<Line> = vec![];
+        // the source line the previous statement ended on
+        let mut last_line = None;
+        for (stmt, depth) in statements.iter().zip(depths) {
+            let depth = depth.unwrap();
+            let end_line = match &stmt.node {
+                ASTNode::Comment(text) => stmt.loc.line + text.matches('\n').count(),
+                _ => stmt.loc.line,
+            };
+            if let ASTNode::Comment(text) = &stmt.node {
+                if last_line == Some(stmt.loc.line)
+                    && lines.last_mut().is_some_and(|line| line.attach(text))
+                {
+                    last_line = Some(end_line);
+                    continue;
+                }
+            }
+            if last_line.is_some_and(|last| stmt.loc.line > last + 1) {
+                lines.push(Line::Blank);
+            }
+            last_line = Some(end_line);
+
+            let line = match stmt.node.instruction() {
+                Some((mnemonic, args)) => Line::Instruction(
+                    depth,
+                    mnemonic,
+                    args.iter().map(|arg| arg.to_string()).collect(),
+                    None,
+                ),
+                None => Line::Text(depth, stmt.node.to_string(), None),
+            };
+            lines.push(line);
+        }
+        Self::render(&lines)
+    }
+
+    /// Prints the lines, aligning the operands and trailing comments of each run of consecutive
+    /// instructions at the same depth.
+    fn render(lines: &[Line]) -> String {
+        let mut out = String::new();
+        let mut i = 0;
+        while i < lines.len() {
+            match &lines[i] {
+                Line::Blank => {
+                    out.push('\n');
+                    i += 1;
+                }
+                Line::Text(depth, text, comment) => {
+                    out.push_str(&INDENT.repeat(*depth));
+                    out.push_str(text);
+                    if let Some(comment) = comment {
+                        out.push(' ');
+                        out.push_str(comment);
+                    }
+                    out.push('\n');
+                    i += 1;
+                }
+                Line::Instruction(depth, ..) => {
+                    let run: Vec<(&str, &Vec<String>, &Option<String>)> = lines[i..]
+                        .iter()
+                        .map_while(|line| match line {
+                            Line::Instruction(d, mnemonic, args, comment) if d == depth => {
+                                Some((*mnemonic, args, comment))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    let mnemonic_width = run.iter().map(|(m, ..)| m.len()).max().unwrap();
+                    let first_width = run
+                        .iter()
+                        .filter(|(_, args, _)| args.len() > 1)
+                        .map(|(_, args, _)| args[0].len())
+                        .max()
+                        .unwrap_or(0);
+                    let code: Vec<String> = run
+                        .iter()
+                        .map(|(mnemonic, args, _)| match args.as_slice() {
+                            [] => mnemonic.to_string(),
+                            [only] => format!("{:<w$} {}", mnemonic, only, w = mnemonic_width),
+                            [first, rest @ ..] => format!(
+                                "{:<w$} {:<f$} {}",
+                                mnemonic,
+                                first,
+                                rest.join(" "),
+                                w = mnemonic_width,
+                                f = first_width
+                            ),
+                        })
+                        .collect();
+                    let code_width = code
+                        .iter()
+                        .zip(&run)
+                        .filter(|(_, (.., comment))| comment.is_some())
+                        .map(|(code, _)| code.len())
+                        .max()
+                        .unwrap_or(0);
+                    for (code, (.., comment)) in code.iter().zip(&run) {
+                        out.push_str(&INDENT.repeat(*depth));
+                        match comment {
+                            Some(comment) => {
+                                out.push_str(&format!("{:<w$} {}", code, comment, w = code_width))
+                            }
+                            None => out.push_str(code),
+                        }
+                        out.push('\n');
+                    }
+                    i += run.len();
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Determines if the label starts a new scope, as opposed to local labels such as `.loop` or `1`.
+fn is_global(name: &str) -> bool {
+    !name.starts_with('.') && !is_numeric_label(name)
+}
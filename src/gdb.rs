@@ -0,0 +1,435 @@
+use std::{marker::PhantomData, num::NonZeroUsize};
+
+use gdbstub::{
+    arch::{Arch, RegId, Registers},
+    common::Signal,
+    conn::{Connection, ConnectionExt},
+    stub::{
+        run_blocking::{BlockingEventLoop, Event, WaitForStopReasonError},
+        DisconnectReason, GdbStub, SingleThreadStopReason,
+    },
+    target::{
+        ext::{
+            base::{
+                single_register_access::{SingleRegisterAccess, SingleRegisterAccessOps},
+                singlethread::{
+                    SingleThreadBase, SingleThreadResume, SingleThreadResumeOps,
+                    SingleThreadSingleStep, SingleThreadSingleStepOps,
+                },
+                BaseOps,
+            },
+            breakpoints::{
+                Breakpoints, BreakpointsOps, HwWatchpoint, HwWatchpointOps, SwBreakpoint,
+                SwBreakpointOps, WatchKind,
+            },
+        },
+        Target, TargetError, TargetResult,
+    },
+};
+use strum::{EnumCount, IntoEnumIterator};
+
+use crate::{
+    cpu::{CpuError, CPU},
+    register::Register,
+};
+
+/// The steps run between checks for an interrupt from the debugger while continuing.
+const STEPS_BETWEEN_POLLS: usize = 1024;
+
+/// The registers in the order of [`Register`], which is how the debugger numbers them.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustystack.cpu">
+    <reg name="ip" bitsize="16" type="code_ptr" regnum="0"/>
+    <reg name="acc" bitsize="16" type="uint16"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="r8" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="bp" bitsize="16" type="data_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// The CPU as the GDB remote protocol sees it: 16 bit big-endian registers and addresses, the
+/// same as in memory. Debuggers that don't learn the byte order from the target description
+/// need `set endian big`.
+pub enum RackArch {}
+
+impl Arch for RackArch {
+    type Usize = u16;
+    type Registers = RackRegisters;
+    type BreakpointKind = usize;
+    type RegId = RackRegId;
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(TARGET_XML)
+    }
+}
+
+/// The value of every register, indexed like [`Register::to_index`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RackRegisters {
+    pub values: [u16; Register::COUNT],
+}
+
+impl Registers for RackRegisters {
+    type ProgramCounter = u16;
+
+    fn pc(&self) -> u16 {
+        self.values[Register::IP.to_index()]
+    }
+
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        for value in self.values {
+            for byte in value.to_be_bytes() {
+                write_byte(Some(byte));
+            }
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() != self.values.len() * 2 {
+            return Err(());
+        }
+        for (value, bytes) in self.values.iter_mut().zip(bytes.chunks(2)) {
+            *value = u16::from_be_bytes([bytes[0], bytes[1]]);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RackRegId(pub Register);
+
+impl RegId for RackRegId {
+    fn from_raw_id(id: usize) -> Option<(Self, Option<NonZeroUsize>)> {
+        Some((RackRegId(Register::from_index(id)?), NonZeroUsize::new(2)))
+    }
+}
+
+/// How the debugger asked the program to resume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Step,
+    Continue,
+}
+
+/// A range of memory the debugger watches, stopping the program when it's accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    addr: u16,
+    len: u16,
+    kind: WatchKind,
+}
+
+impl Watchpoint {
+    /// Determines if the access of `bytes` bytes at `addr` touches the watched memory.
+    fn overlaps(&self, addr: u16, bytes: usize) -> bool {
+        let (start, end) = (addr as usize, addr as usize + bytes);
+        start < self.addr as usize + self.len as usize && (self.addr as usize) < end
+    }
+}
+
+/// Why the debugging session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The program halted with the given exit status
+    Exited(u8),
+    /// The debugger detached, leaving the program where it was
+    Detached,
+    /// The debugger killed the program
+    Killed,
+}
+
+/// A program being debugged over the GDB remote serial protocol, with the breakpoints and
+/// watchpoints the debugger set.
+pub struct GdbTarget<'a> {
+    cpu: &'a CPU,
+    mode: Mode,
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+impl<'a> GdbTarget<'a> {
+    /// Creates a target for the program, which must already be loaded into the CPU.
+    pub fn new(cpu: &'a CPU) -> GdbTarget<'a> {
+        GdbTarget {
+            cpu,
+            mode: Mode::Continue,
+            breakpoints: vec![],
+            watchpoints: vec![],
+        }
+    }
+
+    /// Serves a debugger on the connection until it detaches, kills the program, or the program
+    /// halts.
+    pub fn serve<C>(&mut self, connection: C) -> Result<Disconnect, String>
+    where
+        C: ConnectionExt<Error = std::io::Error>,
+    {
+        let stub = GdbStub::new(connection);
+        match stub.run_blocking::<EventLoop<'a, C>>(self) {
+            Ok(DisconnectReason::TargetExited(status)) => Ok(Disconnect::Exited(status)),
+            Ok(DisconnectReason::Disconnect) => Ok(Disconnect::Detached),
+            Ok(DisconnectReason::Kill) | Ok(DisconnectReason::TargetTerminated(_)) => {
+                Ok(Disconnect::Killed)
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Executes one instruction, returning why the program stopped if it did.
+    fn step(&mut self) -> Option<SingleThreadStopReason<u16>> {
+        match self.cpu.step() {
+            Ok(true) => {
+                let status = self.cpu.exit_status().unwrap_or_default();
+                return Some(SingleThreadStopReason::Exited(
+                    u8::try_from(status).unwrap_or(u8::MAX),
+                ));
+            }
+            Ok(false) => (),
+            Err(CpuError::InvalidAddress(_)) => {
+                return Some(SingleThreadStopReason::Signal(Signal::SIGSEGV))
+            }
+            Err(_) => return Some(SingleThreadStopReason::Signal(Signal::SIGILL)),
+        }
+
+        let effects = self.cpu.effects();
+        for watchpoint in &self.watchpoints {
+            let written = effects
+                .writes
+                .iter()
+                .any(|(addr, bytes)| watchpoint.overlaps(*addr, bytes.len()));
+            let read = effects
+                .reads
+                .iter()
+                .any(|(addr, bytes)| watchpoint.overlaps(*addr, bytes.len()));
+            let hit = match watchpoint.kind {
+                WatchKind::Write => written,
+                WatchKind::Read => read,
+                WatchKind::ReadWrite => written || read,
+            };
+            if hit {
+                return Some(SingleThreadStopReason::Watch {
+                    tid: (),
+                    kind: watchpoint.kind,
+                    addr: watchpoint.addr,
+                });
+            }
+        }
+        let ip = self.cpu.get_register(&Register::IP);
+        if self.breakpoints.contains(&ip) {
+            return Some(SingleThreadStopReason::SwBreak(()));
+        }
+        None
+    }
+}
+
+impl Target for GdbTarget<'_> {
+    type Arch = RackArch;
+    type Error = CpuError;
+
+    fn base_ops(&mut self) -> BaseOps<'_, RackArch, CpuError> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadBase for GdbTarget<'_> {
+    fn read_registers(&mut self, regs: &mut RackRegisters) -> TargetResult<(), Self> {
+        for reg in Register::iter() {
+            regs.values[reg.to_index()] = self.cpu.get_register(&reg);
+        }
+        Ok(())
+    }
+
+    fn write_registers(&mut self, regs: &RackRegisters) -> TargetResult<(), Self> {
+        for reg in Register::iter() {
+            self.cpu.set_register(&reg, regs.values[reg.to_index()]);
+        }
+        Ok(())
+    }
+
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<'_, (), Self>> {
+        Some(self)
+    }
+
+    fn read_addrs(&mut self, start_addr: u16, data: &mut [u8]) -> TargetResult<usize, Self> {
+        let memory = self.cpu.memory();
+        let start = start_addr as usize;
+        let end = (start + data.len()).min(memory.len());
+        let bytes = memory.get_buf(start, end).ok_or(TargetError::NonFatal)?;
+        data[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn write_addrs(&mut self, start_addr: u16, data: &[u8]) -> TargetResult<(), Self> {
+        let memory = self.cpu.memory();
+        let start = start_addr as usize;
+        if start + data.len() > memory.len() {
+            return Err(TargetError::NonFatal);
+        }
+        memory.set_buf(start, start + data.len(), data);
+        Ok(())
+    }
+
+    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleRegisterAccess<()> for GdbTarget<'_> {
+    fn read_register(
+        &mut self,
+        _tid: (),
+        reg_id: RackRegId,
+        buf: &mut [u8],
+    ) -> TargetResult<usize, Self> {
+        let value = self.cpu.get_register(&reg_id.0).to_be_bytes();
+        buf[..2].copy_from_slice(&value);
+        Ok(2)
+    }
+
+    fn write_register(
+        &mut self,
+        _tid: (),
+        reg_id: RackRegId,
+        val: &[u8],
+    ) -> TargetResult<(), Self> {
+        let value: [u8; 2] = val.try_into().map_err(|_| TargetError::NonFatal)?;
+        self.cpu.set_register(&reg_id.0, u16::from_be_bytes(value));
+        Ok(())
+    }
+}
+
+impl SingleThreadResume for GdbTarget<'_> {
+    fn resume(&mut self, signal: Option<Signal>) -> Result<(), CpuError> {
+        // there's nothing a signal could be delivered to
+        let _ = signal;
+        self.mode = Mode::Continue;
+        Ok(())
+    }
+
+    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SingleThreadSingleStep for GdbTarget<'_> {
+    fn step(&mut self, signal: Option<Signal>) -> Result<(), CpuError> {
+        let _ = signal;
+        self.mode = Mode::Step;
+        Ok(())
+    }
+}
+
+impl Breakpoints for GdbTarget<'_> {
+    fn support_sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl SwBreakpoint for GdbTarget<'_> {
+    fn add_sw_breakpoint(&mut self, addr: u16, _kind: usize) -> TargetResult<bool, Self> {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+        Ok(true)
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u16, _kind: usize) -> TargetResult<bool, Self> {
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|at| *at != addr);
+        Ok(self.breakpoints.len() != before)
+    }
+}
+
+impl HwWatchpoint for GdbTarget<'_> {
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: u16,
+        len: u16,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        if len == 0 {
+            return Ok(false);
+        }
+        self.watchpoints.push(Watchpoint { addr, len, kind });
+        Ok(true)
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: u16,
+        len: u16,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let watchpoint = Watchpoint { addr, len, kind };
+        match self.watchpoints.iter().position(|w| *w == watchpoint) {
+            Some(at) => {
+                self.watchpoints.remove(at);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Runs the program between the debugger's requests, watching the connection for interrupts.
+struct EventLoop<'a, C>(PhantomData<(&'a CPU, C)>);
+
+impl<'a, C> BlockingEventLoop for EventLoop<'a, C>
+where
+    C: ConnectionExt<Error = std::io::Error>,
+{
+    type Target = GdbTarget<'a>;
+    type Connection = C;
+    type StopReason = SingleThreadStopReason<u16>;
+
+    #[allow(clippy::type_complexity)]
+    fn wait_for_stop_reason(
+        target: &mut GdbTarget<'a>,
+        conn: &mut C,
+    ) -> Result<
+        Event<SingleThreadStopReason<u16>>,
+        WaitForStopReasonError<CpuError, <C as Connection>::Error>,
+    > {
+        if target.mode == Mode::Step {
+            let stop = target.step();
+            return Ok(Event::TargetStopped(
+                stop.unwrap_or(SingleThreadStopReason::DoneStep),
+            ));
+        }
+        let mut steps = 0;
+        loop {
+            if steps % STEPS_BETWEEN_POLLS == 0 && conn.peek().map_or(true, |b| b.is_some()) {
+                let byte = conn.read().map_err(WaitForStopReasonError::Connection)?;
+                return Ok(Event::IncomingData(byte));
+            }
+            steps += 1;
+            if let Some(stop) = target.step() {
+                return Ok(Event::TargetStopped(stop));
+            }
+        }
+    }
+
+    fn on_interrupt(
+        _target: &mut GdbTarget<'a>,
+    ) -> Result<Option<SingleThreadStopReason<u16>>, CpuError> {
+        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+    }
+}
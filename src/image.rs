@@ -0,0 +1,148 @@
+use std::ops::Range;
+
+use crate::{cpu::CpuError, memory::Memory, to_u16};
+
+/// The bytes every image starts with.
+pub const IMAGE_MAGIC: &[u8; 4] = b"RACK";
+
+/// The version of the image format written by [`Image::to_bytes`].
+pub const IMAGE_VERSION: u8 = 1;
+
+/// A compact, loadable program. Instead of a whole memory buffer, an image only holds the ranges
+/// of memory that the program uses, and the address execution starts at.
+///
+/// The binary layout, with every number stored big endian, is:
+///
+/// ```text
+/// magic "RACK" | version: u8 | entry: u16 | segment count: u16
+/// then for each segment: address: u16 | length: u16 | bytes
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub entry: u16,
+    pub segments: Vec<Segment>,
+}
+
+/// A contiguous run of bytes to be loaded at the given address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u16,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Creates an image out of the given ranges of the memory buffer.
+    pub fn from_memory(memory: &Memory, ranges: &[Range<usize>], entry: u16) -> Image {
+        let segments = ranges
+            .iter()
+            .map(|range| Segment {
+                addr: range.start as u16,
+                data: memory.get_buf(range.start, range.end).unwrap(),
+            })
+            .collect();
+        Image { entry, segments }
+    }
+
+    /// Serializes the image into its binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = IMAGE_MAGIC.to_vec();
+        bytes.push(IMAGE_VERSION);
+        bytes.extend(self.entry.to_be_bytes());
+        bytes.extend((self.segments.len() as u16).to_be_bytes());
+        for segment in &self.segments {
+            bytes.extend(segment.addr.to_be_bytes());
+            bytes.extend((segment.data.len() as u16).to_be_bytes());
+            bytes.extend(&segment.data);
+        }
+        bytes
+    }
+
+    /// Deserializes an image from its binary format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Image, ImageError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(IMAGE_MAGIC.len())? != IMAGE_MAGIC {
+            return Err(ImageError::InvalidMagic);
+        }
+        let version = reader.take(1)?[0];
+        if version != IMAGE_VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+        let entry = reader.take_u16()?;
+        let count = reader.take_u16()?;
+        let mut segments = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let addr = reader.take_u16()?;
+            let len = reader.take_u16()?;
+            let data = reader.take(len as usize)?.to_vec();
+            segments.push(Segment { addr, data });
+        }
+        if reader.pos != bytes.len() {
+            return Err(ImageError::TrailingBytes(bytes.len() - reader.pos));
+        }
+        Ok(Image { entry, segments })
+    }
+
+    /// Determines if the given bytes look like an image, judging by the magic number.
+    pub fn is_image(bytes: &[u8]) -> bool {
+        bytes.starts_with(IMAGE_MAGIC)
+    }
+
+    /// Writes the segments of the image into the memory buffer. Nothing is written unless every
+    /// segment fits.
+    pub fn load_into(&self, memory: &Memory) -> Result<(), CpuError> {
+        if let Some(segment) = self
+            .segments
+            .iter()
+            .find(|segment| segment.addr as usize + segment.data.len() > memory.len())
+        {
+            return Err(CpuError::InvalidAddress(segment.addr));
+        }
+        for segment in &self.segments {
+            let start = segment.addr as usize;
+            memory.set_buf(start, start + segment.data.len(), &segment.data);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the binary format front to back.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ImageError> {
+        let taken = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or(ImageError::Truncated)?;
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn take_u16(&mut self) -> Result<u16, ImageError> {
+        Ok(to_u16(self.take(2)?))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImageError {
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    TrailingBytes(usize),
+}
+
+impl std::error::Error for ImageError {}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ImageError::InvalidMagic => write!(f, "Not an image: invalid magic number"),
+            ImageError::UnsupportedVersion(v) => write!(f, "Unsupported image version: {}", v),
+            ImageError::Truncated => write!(f, "Image is truncated"),
+            ImageError::TrailingBytes(n) => write!(f, "Image has {} trailing bytes", n),
+        }
+    }
+}
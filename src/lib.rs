@@ -5,6 +5,18 @@ pub mod register;
 pub mod parser;
 pub mod ast;
 pub mod assembler;
+pub mod diagnostics;
+pub mod image;
+pub mod disassembler;
+pub mod syscalls;
+pub mod optimizer;
+pub mod sources;
+pub mod formatter;
+pub mod analysis;
+pub mod debugger;
+pub mod trace;
+pub mod gdb;
+pub mod cli;
 
 /// The size of a register in bytes. 16 bits in this case.
 pub const REGISTER_SIZE: usize = 2;
@@ -16,13 +28,42 @@ pub fn to_u16(iter: &[u8]) -> u16 {
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+
     use crate::{
-        cpu::CPU,
+        analysis::{Analysis, CompletionKind},
+        assembler::{Assembler, AssemblerError, AssemblerOptions},
+        cli::{Args, Failure, Parsed, USAGE},
+        ast::{ASTArg, ASTNode, Address},
+        cpu::{CpuError, CPU},
+        debugger::{Debugger, DebuggerError, Reply},
+        diagnostics::Severity,
+        disassembler::Disassembler,
+        formatter::Formatter,
+        gdb::{Disconnect, GdbTarget},
+        image::{Image, ImageError, Segment},
         memory::{InspectableAddr, Memory, MemoryBuilder},
-        opcodes::OpCode,
+        opcodes::{IndexedOperand, OpCode, Operand, OperandKind, INSTRUCTIONS},
+        optimizer::OptLevel,
+        parser::ASTParser,
         register::Register,
+        sources::SourceSet,
+        syscalls::{Syscall, SyscallRegistry},
+        trace::Tracer,
     };
 
+    /// Creates a fresh directory under the system temp dir with the given files written into it.
+    fn temp_sources(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustystack-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
 
     #[test]
     fn get_index_reg() {
@@ -365,4 +406,1390 @@ mod tests {
         cpu.step().unwrap();
         assert_eq!("IP: 0x10, ACC: 0x6, R1: 0x3, R2: 0x2, R3: 0x0, R4: 0x0, R5: 0x0, R6: 0x0, R7: 0x0, R8: 0x0, SP: 0xFEFA, BP: 0xFEFE", cpu.to_string());
     }
+
+    #[test]
+    fn test_include_relative_and_search_paths() {
+        let dir = temp_sources(
+            "include",
+            &[
+                ("main.rack", ".include \"local.rack\"\n.include \"shared.rack\"\nhlt\n"),
+                ("local.rack", "local:\nnop\n"),
+                ("lib/shared.rack", "shared:\nret\n"),
+            ],
+        );
+        let main = dir.join("main.rack");
+        let main = main.to_str().unwrap();
+
+        let diagnostics = match ASTParser::parse_file(main).unwrap_err() {
+            AssemblerError::Diagnostics(diagnostics) => diagnostics,
+            e => panic!("expected diagnostics, got {}", e),
+        };
+        let missing = diagnostics.iter().next().unwrap();
+        assert_eq!(missing.message, "Include not found: shared.rack");
+        assert_eq!(missing.loc.as_ref().unwrap().line, 2);
+
+        let ast = ASTParser::parse_file_with(main, &[dir.join("lib")]).unwrap();
+        let nodes: Vec<ASTNode> = ast.iter().map(|stmt| stmt.node.clone()).collect();
+        assert_eq!(
+            nodes,
+            vec![
+                ASTNode::Label("local".to_string()),
+                ASTNode::Nop,
+                ASTNode::Label("shared".to_string()),
+                ASTNode::Ret,
+                ASTNode::Hlt,
+            ]
+        );
+        assert!(ast[2].loc.file.ends_with("shared.rack"));
+        assert_eq!(ast[3].loc.line, 2);
+        assert!(ast[4].loc.file.ends_with("main.rack"));
+        assert_eq!(ast[4].loc.line, 3);
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = temp_sources(
+            "include-cycle",
+            &[
+                ("a.rack", ".include \"b.rack\"\n"),
+                ("b.rack", "nop\n.include \"a.rack\"\n"),
+            ],
+        );
+        let err = ASTParser::parse_file(dir.join("a.rack").to_str().unwrap()).unwrap_err();
+        let diagnostics = match err {
+            AssemblerError::Diagnostics(diagnostics) => diagnostics,
+            e => panic!("expected a recursive include error, got {}", e),
+        };
+        assert_eq!(diagnostics.len(), 1);
+        let cycle = diagnostics.iter().next().unwrap();
+        let chain: Vec<&str> = cycle
+            .message
+            .strip_prefix("Recursive include: ")
+            .unwrap()
+            .split(" -> ")
+            .collect();
+        assert_eq!(chain.len(), 3);
+        assert!(chain[2].ends_with("a.rack"));
+        let loc = cycle.loc.as_ref().unwrap();
+        assert!(loc.file.ends_with("b.rack"));
+        assert_eq!(loc.line, 2);
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let mut ast = ASTParser::parse_str(
+            "first:\n.loop:\n1:\njmp .loop\njmp 1b\njmp 1f\n1:\n\
+             second:\n.loop:\n1:\njmp .loop\njmp 1b\n",
+        )
+        .unwrap();
+        Assembler::qualify_labels(&mut ast);
+        let nodes: Vec<ASTNode> = ast.into_iter().map(|stmt| stmt.node).collect();
+        let label = |name: &str| ASTNode::Label(name.to_string());
+        let jmp = |name: &str| ASTNode::Jmp(ASTArg::Label(name.to_string()));
+        assert_eq!(
+            nodes,
+            vec![
+                label("first"),
+                label("first.loop"),
+                label("first.1@2"),
+                jmp("first.loop"),
+                jmp("first.1@2"),
+                jmp("first.1@6"),
+                label("first.1@6"),
+                label("second"),
+                label("second.loop"),
+                label("second.1@9"),
+                jmp("second.loop"),
+                jmp("second.1@9"),
+            ]
+        );
+
+        let ast = ASTParser::parse_str("a:\n1:\nb:\njmp 1b\n").unwrap();
+        let loc = ast[3].loc.clone();
+        let err = Assembler::assemble(ast).err().unwrap();
+        assert!(err.to_string().contains(&format!("{}: error: Invalid label: 1b", loc)));
+    }
+
+    #[test]
+    fn test_assembler_diagnostics() {
+        // diagnostics come out in source order, whichever pass found them
+        let ast = ASTParser::parse_str(
+            "start:\nsys 300\nunused:\nstart:\njmp missing\njmp start\n",
+        )
+        .unwrap();
+        let diagnostics = Assembler::assemble_with(ast.clone(), &AssemblerOptions::default())
+            .err()
+            .unwrap();
+        let messages: Vec<(Severity, String, usize)> = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.message.clone(), d.loc.as_ref().unwrap().line))
+            .collect();
+        let main = &ast[0].loc.file;
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    Severity::Warning,
+                    "Value 300 doesn't fit in a byte, truncated to 44".to_string(),
+                    2
+                ),
+                (Severity::Warning, "Unused label: unused".to_string(), 3),
+                (
+                    Severity::Error,
+                    format!("Duplicate label: start (previously defined at {}:1:1)", main),
+                    4
+                ),
+                (Severity::Error, "Invalid label: missing".to_string(), 5),
+            ]
+        );
+
+        // included sources come in place of their `.include`, whatever their names
+        let mut sources = SourceSet::new();
+        sources
+            .add("main.rack", "start:\n.include \"zlib.rack\"\njmp missing\n")
+            .add("zlib.rack", "sys 300\nunused:\n");
+        let ast = ASTParser::parse_with(&sources, "main.rack").unwrap();
+        let diagnostics = Assembler::assemble_with(ast, &AssemblerOptions::default())
+            .err()
+            .unwrap();
+        let locations: Vec<(&str, usize)> = diagnostics
+            .iter()
+            .map(|d| {
+                let loc = d.loc.as_ref().unwrap();
+                (&*loc.file, loc.line)
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            [
+                ("main.rack", 1),
+                ("zlib.rack", 1),
+                ("zlib.rack", 2),
+                ("main.rack", 3)
+            ]
+        );
+
+        let ast = ASTParser::parse_str("unused:\nhlt\n").unwrap();
+        let assembly = Assembler::assemble_with(ast.clone(), &AssemblerOptions::default()).unwrap();
+        assert_eq!(assembly.diagnostics.count(Severity::Warning), 1);
+        let options = AssemblerOptions {
+            werror: true,
+            ..AssemblerOptions::default()
+        };
+        let diagnostics = Assembler::assemble_with(ast, &options).err().unwrap();
+        assert_eq!(diagnostics.count(Severity::Error), 1);
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let ast = ASTParser::parse_str("mov 5 r1\nmov 10 r2\nadd r1 r2\nhlt\n").unwrap();
+        let image = Assembler::assemble_with(ast, &AssemblerOptions::default())
+            .unwrap()
+            .to_image();
+        assert_eq!(image.entry, 0);
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].data.len(), 12);
+
+        let bytes = image.to_bytes();
+        assert_eq!(&bytes[..9], b"RACK\x01\x00\x00\x00\x01");
+        assert_eq!(Image::from_bytes(&bytes).unwrap(), image);
+        assert_eq!(
+            Image::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ImageError::Truncated)
+        );
+        assert_eq!(Image::from_bytes(b"KCAR\x01"), Err(ImageError::InvalidMagic));
+
+        let cpu = CPU::new(Memory::default());
+        cpu.load_image(&image).unwrap();
+        cpu.run().unwrap();
+        assert_eq!(cpu.get_register(&Register::ACC), 15);
+
+        let too_big = Image {
+            entry: 0,
+            segments: vec![Segment {
+                addr: 90,
+                data: vec![0; 20],
+            }],
+        };
+        assert!(CPU::new(Memory::new(100)).load_image(&too_big).is_err());
+
+        // a segment that doesn't fit keeps the others from being loaded
+        let partly_too_big = Image {
+            entry: 0,
+            segments: vec![
+                Segment {
+                    addr: 0,
+                    data: vec![0xAA; 4],
+                },
+                too_big.segments[0].clone(),
+            ],
+        };
+        let memory = Memory::new(100);
+        assert!(partly_too_big.load_into(&memory).is_err());
+        assert_eq!(memory.get_buf(0, 4).unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn test_org_and_entry() {
+        let ast = ASTParser::parse_str(
+            ".entry start\n.org 0x10\ndata:\nnop\n.org 0x20\nstart:\nmov 7 r1\nhlt\n",
+        )
+        .unwrap();
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        assert_eq!(assembly.entry, 0x20);
+        assert_eq!(assembly.used, vec![0x10..0x11, 0x20..0x25]);
+        let image = assembly.to_image();
+        assert_eq!(image.segments[1].addr, 0x20);
+
+        let cpu = CPU::new(Memory::default());
+        cpu.load_image(&image).unwrap();
+        assert_eq!(cpu.get_register(&Register::IP), 0x20);
+        while !cpu.step().unwrap() {}
+        assert_eq!(cpu.get_register(&Register::R1), 7);
+
+        let ast = ASTParser::parse_str("mov 1 r1\nmov 2 r2\n.org 0x4\nhlt\n").unwrap();
+        let diagnostics = Assembler::assemble_with(ast, &AssemblerOptions::default())
+            .err()
+            .unwrap();
+        let overlap = diagnostics.iter().next().unwrap();
+        assert_eq!(
+            overlap.message,
+            "Section 0x0004..0x0005 overlaps section 0x0000..0x0008"
+        );
+        assert_eq!(overlap.loc.as_ref().unwrap().line, 3);
+    }
+
+    #[test]
+    fn test_org_sections_with_patches() {
+        // patching a label moves the counter back, which mustn't end the section there
+        for source in [
+            ".org 0x10\nstart:\njmp start\n.org 0x0\nnop\n",
+            ".org 0x10\njmp end\nend:\nhlt\n.org 0x0\nnop\n",
+        ] {
+            let ast = ASTParser::parse_str(source).unwrap();
+            let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+            assert_eq!(assembly.used[0], 0x0..0x1);
+        }
+
+        for source in [".org 0xFFFC\nmov 1 r1\n", ".org 0xFFFC\n.byte \"abcd\"\n"] {
+            let ast = ASTParser::parse_str(source).unwrap();
+            let diagnostics = Assembler::assemble_with(ast, &AssemblerOptions::default())
+                .err()
+                .unwrap();
+            let error = diagnostics.iter().next().unwrap();
+            assert_eq!(error.message, "Code runs past the end of memory at 0xFFFC");
+            assert_eq!(error.loc.as_ref().unwrap().line, 2);
+        }
+        let ast = ASTParser::parse_str(".org 0xFFFC\n.byte \"abc\"\n").unwrap();
+        assert!(Assembler::assemble_with(ast, &AssemblerOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_instruction_table_round_trip() {
+        for (i, def) in INSTRUCTIONS.iter().enumerate() {
+            assert_eq!(def.opcode as usize, i);
+            assert_eq!(OpCode::try_from(def.byte).unwrap(), def.opcode);
+            let operands: Vec<Operand> = def
+                .operands
+                .iter()
+                .enumerate()
+                .map(|(i, kind)| match kind {
+                    OperandKind::Reg | OperandKind::RegPtr => Operand::Reg(Register::R3),
+                    OperandKind::Byte => Operand::Value(0x42 + i as u16),
+                    OperandKind::Indexed => Operand::Indexed(IndexedOperand {
+                        base: Some(Register::R3),
+                        index: None,
+                        scale: 4,
+                        displacement: 0xFFFE,
+                    }),
+                    _ => Operand::Value(0x1234 + i as u16),
+                })
+                .collect();
+            let bytes = def.encode(&operands);
+            assert_eq!(bytes.len(), def.size());
+            assert_eq!(def.decode_operands(&bytes[1..]).unwrap(), operands);
+        }
+        assert!(OpCode::try_from(0xFF).is_err());
+    }
+
+    #[test]
+    fn test_assembled_encodings_match_cpu() {
+        let ast = ASTParser::parse_str(
+            "mov 0b1100 r1\nmov 0b1010 r2\nxor r1 r2\nmov acc r3\n\
+             sub 2 r1\nmov acc r4\nsub r1 20\nmov acc r5\n\
+             mov 0x1234 [0x100]\nmov [0x100] r6\nmov 0x200 r7\nmov r1 [r7]\nmov [r7] r8\n\
+             mov 0 acc\nloop:\ninc acc\njne loop 3\nhlt\n",
+        )
+        .unwrap();
+        let cpu = CPU::new(Assembler::assemble(ast).unwrap());
+        let mut steps = 0;
+        while !cpu.step().unwrap() {
+            steps += 1;
+            assert!(steps < 100, "the program should halt");
+        }
+        assert_eq!(cpu.get_register(&Register::R3), 0b0110);
+        assert_eq!(cpu.get_register(&Register::R4), 10);
+        assert_eq!(cpu.get_register(&Register::R5), 8);
+        assert_eq!(cpu.get_register(&Register::R6), 0x1234);
+        assert_eq!(cpu.get_register(&Register::R8), 12);
+        assert_eq!(cpu.get_register(&Register::ACC), 3);
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        let source = "\
+.entry main
+.org 0x8
+main:
+mov 5 r1
+.loop:
+dec r1
+mov r1 acc
+jne .loop 0
+mov [counter] r2
+cal done
+hlt
+done:
+ret
+counter:
+.byte 0xFF 0xFE 0x02
+";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let image = assembly.to_image();
+        let lines = Disassembler::new(&assembly.memory)
+            .with_symbols(assembly.labels())
+            .disassemble_image(&image);
+        let text = Disassembler::to_source(&lines);
+        assert!(text.contains("main.loop:\n  dec r1\n"), "{}", text);
+        assert!(text.contains("  jne main.loop 0x0\n"), "{}", text);
+        assert!(text.contains("  mov [counter] r2\n"), "{}", text);
+        // 0xFF isn't an opcode, so the bytes after `counter` are data
+        assert!(text.ends_with("counter:\n  .byte 0xFF 0xFE 0x2\n"), "{}", text);
+
+        let ast = ASTParser::parse_str(&text).unwrap();
+        let reassembled = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        assert_eq!(reassembled.to_image(), image);
+
+        // without symbols, addresses are kept as literals
+        let lines = Disassembler::new(&assembly.memory).disassemble(0x8..0xE);
+        assert_eq!(lines[0].node, ASTNode::Org(ASTArg::Lit(0x8)));
+        assert_eq!(lines[1].addr, 0x8);
+        assert_eq!(lines[1].bytes, vec![0x10, 0x00, 0x05, 0x02]);
+        assert_eq!(
+            lines[1].node,
+            ASTNode::Mov(ASTArg::Lit(5), ASTArg::Reg(Register::R1))
+        );
+        assert_eq!(lines[2].node, ASTNode::Dec(ASTArg::Reg(Register::R1)));
+    }
+
+    #[test]
+    fn test_label_immediates() {
+        let source = "\
+mov table r1
+mov [r1] r2
+psh second
+pop r3
+mov second acc
+jne fail second
+add table r4
+hlt
+fail:
+mov 1 r8
+hlt
+table:
+.byte 0x12 0x34
+second:
+.byte 0x56
+";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let table = assembly.symbols["table"];
+        let cpu = CPU::new(assembly.memory);
+        while !cpu.step().unwrap() {}
+        assert_eq!(cpu.get_register(&Register::R1), table);
+        assert_eq!(cpu.get_register(&Register::R2), 0x1234);
+        assert_eq!(cpu.get_register(&Register::R3), table + 2);
+        assert_eq!(cpu.get_register(&Register::ACC), table);
+        assert_eq!(cpu.get_register(&Register::R8), 0);
+
+        // labels are addresses, which don't fit in a byte operand, so `sys` takes syscall names
+        let source = "sys table\ntable:\nhlt\n";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let diagnostics = Assembler::assemble_with(ast, &AssemblerOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            diagnostics.iter().next().unwrap().message,
+            "Unknown syscall: table"
+        );
+    }
+
+    #[test]
+    fn test_indirect_jumps_and_tables() {
+        let source = "\
+mov 1 r1
+shl r1 1
+add table r1
+mov acc r2
+jmp [r2]
+zero:
+mov 10 r3
+hlt
+one:
+mov 11 r3
+mov indirect r4
+jmp r4
+mov 99 r5
+indirect:
+jmp [pointer]
+mov 99 r5
+done:
+hlt
+table:
+.table zero one
+pointer:
+.table done
+";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let table = assembly.symbols["table"] as usize;
+        assert_eq!(
+            assembly.memory.get_buf(table, table + 6).unwrap(),
+            [
+                assembly.symbols["zero"].to_be_bytes(),
+                assembly.symbols["one"].to_be_bytes(),
+                assembly.symbols["done"].to_be_bytes(),
+            ]
+            .concat()
+        );
+        let cpu = CPU::new(assembly.memory);
+        while !cpu.step().unwrap() {}
+        assert_eq!(cpu.get_register(&Register::R3), 11);
+        assert_eq!(cpu.get_register(&Register::R5), 0);
+    }
+
+    #[test]
+    fn test_position_independent_code() {
+        let source = "\
+mov 3 r1
+mov counter r2
+cal count
+hlt
+count:
+dec r1
+mov r1 acc
+jne count 0
+ret
+counter:
+.byte 0
+";
+        let options = AssemblerOptions {
+            pic: true,
+            ..AssemblerOptions::default()
+        };
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &options).unwrap();
+        let counter = assembly.symbols["counter"];
+        assert_eq!(assembly.memory.get(4).unwrap(), u8::from(OpCode::MovRelReg));
+        assert_eq!(assembly.memory.get(8).unwrap(), u8::from(OpCode::CalRel));
+
+        // the same image runs wherever it's loaded
+        let mut image = assembly.to_image();
+        for base in [0, 0x100, 0x1234] {
+            image.segments[0].addr = base;
+            image.entry = base;
+            let cpu = CPU::new(Memory::default());
+            cpu.load_image(&image).unwrap();
+            while !cpu.step().unwrap() {}
+            assert_eq!(cpu.get_register(&Register::R1), 0);
+            assert_eq!(cpu.get_register(&Register::R2), base + counter);
+        }
+
+        // disassembly keeps relative operands relative, assembled without -fpic or not
+        let end = assembly.used[0].end;
+        let source = Disassembler::to_source(
+            &Disassembler::new(&assembly.memory)
+                .with_symbols(assembly.labels())
+                .disassemble(0..end),
+        );
+        assert!(source.contains("  cal $+0x"), "{}", source);
+        assert!(source.contains("  jne $-0x"), "{}", source);
+        let ast = ASTParser::parse_str(&source).unwrap();
+        let reassembled = Assembler::assemble(ast).unwrap();
+        assert_eq!(
+            reassembled.get_buf(0, end),
+            assembly.memory.get_buf(0, end)
+        );
+        let ast = ASTParser::parse_str("jmp $\n").unwrap();
+        assert_eq!(
+            Assembler::assemble(ast).unwrap().get_buf(0, 3).unwrap(),
+            [u8::from(OpCode::JmpRel), 0xFF, 0xFD]
+        );
+
+        // absolute references to labels can't be relocated
+        let ast = ASTParser::parse_str("mov [data] r1\ndata:\n").unwrap();
+        let diagnostics = Assembler::assemble_with(ast, &options).err().unwrap();
+        assert_eq!(
+            diagnostics.iter().next().unwrap().message,
+            "Absolute reference to label data in position-independent code"
+        );
+    }
+
+    #[test]
+    fn test_named_syscalls() {
+        let mut syscalls = SyscallRegistry::default();
+        let double = Syscall {
+            number: 0x10,
+            name: "double_acc",
+            description: "Doubles the value of the acc register",
+            handler: |cpu| {
+                let acc = cpu.get_register(&Register::ACC);
+                cpu.set_register(&Register::ACC, acc * 2);
+                Ok(())
+            },
+        };
+        syscalls.register(double).unwrap();
+        assert_eq!(syscalls.register(double).err().unwrap().name, "double_acc");
+        assert_eq!(
+            syscalls.iter().map(|s| s.name).collect::<Vec<_>>(),
+            ["print_acc", "exit", "double_acc"]
+        );
+
+        let options = AssemblerOptions {
+            syscalls: syscalls.clone(),
+            ..AssemblerOptions::default()
+        };
+        let source = "mov 21 acc\nsys double_acc\nhlt\n";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &options).unwrap();
+        assert_eq!(assembly.memory.get_buf(4, 6).unwrap(), [0x3F, 0x10]);
+        let lines = Disassembler::new(&assembly.memory)
+            .with_syscalls(syscalls.clone())
+            .disassemble(4..6);
+        assert_eq!(lines[1].node.to_string(), "sys double_acc");
+
+        let cpu = CPU::new(assembly.memory).with_syscalls(syscalls);
+        while !cpu.step().unwrap() {}
+        assert_eq!(cpu.get_register(&Register::ACC), 42);
+
+        // the default registry only knows the builtin syscalls
+        let ast = ASTParser::parse_str(source).unwrap();
+        let diagnostics = Assembler::assemble_with(ast, &AssemblerOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            diagnostics.iter().next().unwrap().message,
+            "Unknown syscall: double_acc"
+        );
+    }
+
+    #[test]
+    fn test_command_line() {
+        let parse = |args: &[&str]| Args::parse(args.iter().map(|arg| arg.to_string()));
+        let usage = |args: &[&str]| match parse(args) {
+            Err(Failure::Usage(message)) => message,
+            other => panic!("expected a usage error for {:?}, got {:?}", args, other),
+        };
+        assert_eq!(usage(&[]), "no command given");
+        assert_eq!(usage(&["frobnicate"]), "unknown command `frobnicate`");
+        assert_eq!(usage(&["run"]), "run needs a file");
+        assert_eq!(usage(&["check", "a.rack", "b.rack"]), "unexpected argument `b.rack`");
+        assert_eq!(
+            usage(&["assemble", "a.rack"]),
+            "assemble needs an output file, given with `-o`"
+        );
+        assert_eq!(usage(&["run", "a.rack", "--trace"]), "`--trace` needs a value");
+        assert_eq!(usage(&["fmt", "-O2", "a.rack"]), "unknown option `-O2` for fmt");
+        assert_eq!(usage(&["gdb", "--port", "x", "a.rack"]), "invalid port `x`");
+        assert_eq!(usage(&["syscalls", "a.rack"]), "syscalls takes no file");
+
+        assert!(matches!(parse(&["--help"]), Ok(Parsed::Help(help)) if help == USAGE));
+        let commands = ["assemble", "run", "debug", "gdb", "disasm", "check", "fmt", "syscalls"];
+        for command in commands {
+            let help = match parse(&[command, "--help"]) {
+                Ok(Parsed::Help(help)) => help,
+                other => panic!("expected the help of {}, got {:?}", command, other),
+            };
+            assert!(help.starts_with(&format!("usage: rustystack {}", command)));
+            assert!(USAGE.contains(&format!("\n    {} ", command)));
+        }
+
+        let args = match parse(&["assemble", "-O2", "-I", "lib", "-Iinc", "a.rack", "-o", "a.bin"]) {
+            Ok(Parsed::Command(args)) => args,
+            other => panic!("expected a command, got {:?}", other),
+        };
+        assert_eq!(args.command, "assemble");
+        assert_eq!(args.file, "a.rack");
+        assert_eq!(args.output.as_deref(), Some("a.bin"));
+        assert_eq!(args.include_paths, [PathBuf::from("lib"), PathBuf::from("inc")]);
+        assert_eq!(args.options.opt_level, OptLevel::O2);
+
+        // a file given without a command is run
+        let dir = temp_sources("command-line", &[("main.rack", "hlt\n")]);
+        let main = dir.join("main.rack");
+        let args = parse(&[main.to_str().unwrap(), "--trace", "trace.jsonl"]);
+        assert!(matches!(args, Ok(Parsed::Command(args)) if args.command == "run"));
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let run = |source: &str| {
+            let ast = ASTParser::parse_str(source).unwrap();
+            let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+            let cpu = CPU::new(Memory::default());
+            cpu.load_image(&assembly.to_image()).unwrap();
+            let status = cpu.run().unwrap();
+            assert_eq!(cpu.exit_status(), Some(status));
+            (status, cpu.get_register(&Register::R1))
+        };
+        assert_eq!(run("mov 7 r1\nhlt\n"), (0, 7));
+        assert_eq!(run("mov 3 acc\nhlt\n"), (3, 0));
+        // exiting stops the program right away
+        assert_eq!(run("mov 300 acc\nsys exit\nmov 1 r1\nhlt\n"), (300, 0));
+        assert_eq!(run("sys print_acc\nmov 1 r1\nhlt\n"), (0, 1));
+    }
+
+    #[test]
+    fn test_stack_bounds() {
+        let run = |source: &str| {
+            let ast = ASTParser::parse_str(source).unwrap();
+            let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+            let cpu = CPU::new(Memory::default());
+            cpu.load_image(&assembly.to_image()).unwrap();
+            let sp = cpu.get_register(&Register::SP);
+            (cpu.run(), sp, cpu.get_register(&Register::SP))
+        };
+        // popping an empty stack fails, leaving sp as it was
+        let (result, start, sp) = run("ret\n");
+        assert!(matches!(result, Err(CpuError::InvalidAddress(_))));
+        assert_eq!(sp, start);
+        let (result, start, sp) = run("pop r1\nhlt\n");
+        assert!(matches!(result, Err(CpuError::InvalidAddress(_))));
+        assert_eq!(sp, start);
+        // so does pushing past the start of memory
+        let (result, _, sp) = run(".org 0x10\nmov 2 sp\npsh 1\npsh 2\nhlt\n");
+        assert!(matches!(result, Err(CpuError::InvalidAddress(0))));
+        assert_eq!(sp, 0);
+    }
+
+    #[test]
+    fn test_debugger() {
+        let source = "\
+main:
+  mov 3 r1
+  cal double
+  mov r1 r2
+.loop:
+  dec r1
+  jne .loop r1
+  hlt
+double:
+  add r1 r1
+  mov acc r1
+  ret
+";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let image = assembly.to_image();
+        let cpu = CPU::new(Memory::default());
+        cpu.load_image(&image).unwrap();
+        let mut debugger = Debugger::new(&cpu, &image).with_symbols(assembly.labels());
+        let mut execute = |command: &str| match debugger.execute(command) {
+            Ok(Reply::Output(output)) => output,
+            Ok(Reply::Quit) => "quit".to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        assert_eq!(execute("break main.loop"), "Breakpoint 1 at 0x000A <main.loop>");
+        assert_eq!(execute("step"), "0x0004 <main+4>: cal double");
+        assert_eq!(execute("step"), "0x0011 <double>: add r1 r1");
+        assert_eq!(execute("finish"), "0x0007 <main+7>: mov r1 r2");
+        assert_eq!(cpu.get_register(&Register::R1), 6);
+        assert_eq!(execute("continue"), "Breakpoint 1, 0x000A <main.loop>: dec r1");
+        assert_eq!(execute("x/4 main"), "0x0000: 10 00 03 02");
+        assert_eq!(execute("set r1 7"), "r1 = 0x0007");
+        assert_eq!(execute("set [0x100] 0x1234"), "[0x0100] = 0x1234");
+        assert_eq!(cpu.memory().get_buf(0x100, 0x102).unwrap(), [0x12, 0x34]);
+        assert!(execute("registers").contains("r1  0x0007  7"));
+        assert!(execute("disassemble").contains("=> 0x000A <main.loop>: dec r1"));
+        assert_eq!(execute("delete 2"), "No breakpoint number 2");
+        assert_eq!(execute("delete 1"), "Deleted breakpoint 1");
+        assert_eq!(execute("bogus"), "Unknown command: bogus, try `help`");
+        assert_eq!(execute("continue"), "The program exited with status 6");
+        assert_eq!(execute("step"), "The program has exited with status 6");
+        assert_eq!(execute("quit"), "quit");
+
+        // next runs over calls, and an empty line repeats the last command
+        let cpu = CPU::new(Memory::default());
+        cpu.load_image(&image).unwrap();
+        let mut debugger = Debugger::new(&cpu, &image).with_symbols(assembly.labels());
+        debugger.execute("next 2").unwrap();
+        assert_eq!(cpu.get_register(&Register::IP), 0x7);
+        assert_eq!(cpu.get_register(&Register::R1), 6);
+        debugger.execute("").unwrap();
+        assert_eq!(cpu.get_register(&Register::IP), 0xC);
+
+        // a call running past the top of memory is stepped into, failing to fetch its operand
+        let call = OpCode::CalLit.def().byte;
+        debugger
+            .execute(&format!("set [0xFFFD] 0x{:02X}00", call))
+            .unwrap();
+        assert!(matches!(
+            debugger.execute("x/18446744073709551615 0"),
+            Err(DebuggerError::Usage(_))
+        ));
+        debugger.execute("set ip 0xFFFD").unwrap();
+        assert!(matches!(
+            debugger.execute("next"),
+            Err(DebuggerError::Cpu(CpuError::InvalidAddress(_)))
+        ));
+    }
+
+    #[test]
+    fn test_trace() {
+        let source = "main:\n  mov 2 acc\n  cal show\n  hlt\nshow:\n  sys print_acc\n  ret\n";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let trace = |filters: &[&str]| {
+            let cpu = CPU::new(Memory::default());
+            cpu.load_image(&assembly.to_image()).unwrap();
+            let mut out = vec![];
+            let mut tracer = Tracer::new(&cpu, &mut out).with_symbols(assembly.labels());
+            for filter in filters {
+                tracer.filter(filter).unwrap();
+            }
+            assert_eq!(tracer.run().unwrap(), 2);
+            let records: Vec<serde_json::Value> = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            records
+        };
+
+        let records = trace(&[]);
+        assert_eq!(records.len(), 5);
+        assert_eq!(
+            records[0],
+            serde_json::json!({
+                "step": 1,
+                "ip": 0,
+                "at": "main",
+                "bytes": "10 00 02 01",
+                "instruction": "mov 0x2 acc",
+                "registers": {"acc": {"old": 0, "new": 2}},
+                "writes": [],
+                "syscall": null,
+            })
+        );
+        assert_eq!(records[1]["instruction"], "cal show");
+        assert_eq!(records[1]["registers"]["sp"]["new"], 0xFFFB);
+        assert_eq!(records[1]["writes"][0]["bytes"], "00 07");
+        assert_eq!(records[2]["at"], "show");
+        assert_eq!(
+            records[2]["syscall"],
+            serde_json::json!({"number": 0, "name": "print_acc"})
+        );
+
+        let steps = |records: Vec<serde_json::Value>| -> Vec<u64> {
+            records.iter().map(|r| r["step"].as_u64().unwrap()).collect()
+        };
+        assert_eq!(steps(trace(&["show"])), [3, 4]);
+        assert_eq!(steps(trace(&["0x4..show", "show.."])), [2, 3, 4, 5]);
+
+        let cpu = CPU::new(Memory::default());
+        let mut tracer = Tracer::new(&cpu, vec![]).with_symbols(assembly.labels());
+        assert_eq!(
+            tracer.filter("nowhere").unwrap_err().to_string(),
+            "Invalid trace filter: nowhere, expected a label or <start>..<end>"
+        );
+    }
+
+    #[test]
+    fn test_gdb_stub() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+
+        /// Sends a packet and returns the expanded reply, acknowledging both ways.
+        fn request(stream: &mut TcpStream, packet: &str) -> String {
+            let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+            let mut byte = [0u8];
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut reply = vec![];
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'#' => break,
+                    // run-length encoding: repeat the last character
+                    b'*' => {
+                        stream.read_exact(&mut byte).unwrap();
+                        let last = *reply.last().unwrap();
+                        reply.extend(std::iter::repeat_n(last, byte[0] as usize - 29));
+                    }
+                    b => reply.push(b),
+                }
+            }
+            stream.read_exact(&mut [0u8; 2]).unwrap();
+            stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+
+        let source = "main:\n  mov 2 acc\n  cal show\n  hlt\nshow:\n  ret\n";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let cpu = CPU::new(Memory::default());
+        cpu.load_image(&assembly.to_image()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut replies = vec![];
+            for packet in [
+                "qSupported:swbreak+;hwbreak+",
+                "?",
+                "m0,4",
+                "P2=0005",
+                "p2",
+                "Z0,8,1",
+                "c",
+                "p0",
+                "z0,8,1",
+                "Z3,fffd,2",
+                "c",
+                "z3,fffd,2",
+                "c",
+            ] {
+                replies.push(request(&mut stream, packet));
+            }
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let disconnect = GdbTarget::new(&cpu).serve(stream).unwrap();
+        let replies = client.join().unwrap();
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[2], "10000201");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(replies[4], "0005");
+        assert!(replies[6].contains("swbreak"));
+        assert_eq!(replies[7], "0008");
+        assert!(replies[10].contains("rwatch"));
+        assert_eq!(replies[12], "W02");
+        assert_eq!(disconnect, Disconnect::Exited(2));
+        assert_eq!(cpu.get_register(&Register::R1), 5);
+    }
+
+    #[test]
+    fn test_optimizer() {
+        let source = "\
+mov 3 r1
+mov r1 r1
+psh 4
+pop r2
+add r1 r2
+mov acc r3
+jmp end
+mov 99 r4
+end:
+hlt
+nop
+";
+        let assemble = |opt_level| {
+            let options = AssemblerOptions {
+                opt_level,
+                werror: true,
+                ..AssemblerOptions::default()
+            };
+            let ast = ASTParser::parse_str(source).unwrap();
+            let assembly = Assembler::assemble_with(ast, &options).unwrap();
+            let notes: Vec<(String, usize)> = assembly
+                .diagnostics
+                .iter()
+                .map(|d| (d.message.clone(), d.loc.as_ref().unwrap().line))
+                .collect();
+            let lines = Disassembler::new(&assembly.memory)
+                .with_symbols(assembly.labels())
+                .disassemble(0..assembly.used[0].end);
+            let cpu = CPU::new(assembly.memory);
+            while !cpu.step().unwrap() {}
+            assert_eq!(cpu.get_register(&Register::R3), 7);
+            assert_eq!(cpu.get_register(&Register::R4), 0);
+            (Disassembler::to_source(&lines), notes)
+        };
+
+        let (text, notes) = assemble(OptLevel::O0);
+        assert!(notes.is_empty());
+        assert!(text.contains("  mov r1 r1\n"));
+
+        let (text, notes) = assemble(OptLevel::O1);
+        assert_eq!(
+            text,
+            "  mov 0x3 r1\n  mov 0x4 r2\n  add r1 r2\n  mov acc r3\n  jmp end\nend:\n  hlt\n"
+        );
+        assert_eq!(
+            notes,
+            [
+                ("Removed redundant move `mov r1 r1`".to_string(), 2),
+                ("Collapsed `psh 0x4` and `pop r2` into `mov 0x4 r2`".to_string(), 3),
+                ("Removed unreachable `mov 0x63 r4`".to_string(), 8),
+                ("Removed unreachable `nop`".to_string(), 11),
+            ]
+        );
+
+        let (text, notes) = assemble(OptLevel::O2);
+        assert!(text.contains("  mov 0x7 acc\n"), "{}", text);
+        assert!(notes.contains(&("Folded `add r1 r2` into `mov 0x7 acc`".to_string(), 5)));
+    }
+
+    #[test]
+    fn test_source_sets_match_files() {
+        let files = [
+            ("main.rack", ".include \"lib/util.rack\"\nstart:\njmp missing\n"),
+            ("lib/util.rack", ".include \"../common.rack\"\nutil:\nsys 300\n"),
+            ("common.rack", "common:\nhlt\n"),
+            ("cycle.rack", "nop\n.include \"./cycle.rack\"\n"),
+        ];
+        let dir = temp_sources("source-set", &files);
+        let mut sources = SourceSet::new();
+        for (name, source) in files {
+            sources.add(dir.join(name).to_str().unwrap(), source);
+        }
+
+        let main = dir.join("main.rack");
+        let from_files = ASTParser::parse_file(main.to_str().unwrap()).unwrap();
+        let from_set = ASTParser::parse_with(&sources, main.to_str().unwrap()).unwrap();
+        assert_eq!(from_set, from_files);
+        let options = AssemblerOptions::default();
+        assert_eq!(
+            Assembler::assemble_with(from_set, &options).err().unwrap().to_string(),
+            Assembler::assemble_with(from_files, &options).err().unwrap().to_string()
+        );
+
+        let cycle = dir.join("cycle.rack");
+        let from_files = ASTParser::parse_file(cycle.to_str().unwrap()).unwrap_err();
+        let from_set = ASTParser::parse_with(&sources, cycle.to_str().unwrap()).unwrap_err();
+        assert!(from_set.to_string().contains("Recursive include"));
+        assert_eq!(from_set.to_string(), from_files.to_string());
+
+        let err = ASTParser::parse_str(".include \"util.rack\"\n").unwrap_err();
+        assert_eq!(
+            err.to_string().trim_end(),
+            "<string>:1:1: error: Include not found: util.rack"
+        );
+
+        // include errors are reported along with syntax errors, before and after them
+        let err = ASTParser::parse_str("mvo 1 r1\n.include \"util.rack\"\nmov 1 r1 r2\n")
+            .unwrap_err()
+            .to_string();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", err);
+        assert!(lines[0].starts_with("<string>:1:1: error:"), "{}", err);
+        assert_eq!(lines[1], "<string>:2:1: error: Include not found: util.rack");
+        assert!(lines[2].starts_with("<string>:3:"), "{}", err);
+
+        // so editors keep the labels of included sources with syntax errors
+        let mut sources = SourceSet::new();
+        sources
+            .add("main.rack", ".include \"lib.rack\"\njmp shared\n")
+            .add("lib.rack", "shared:\nmvo 1 r1\n");
+        let analysis = Analysis::new(&sources, "main.rack");
+        assert_eq!(&*analysis.definition(2, 5).unwrap().loc.file, "lib.rack");
+
+        // `..` doesn't go past the root, nor past the start of a relative name
+        let mut sources = SourceSet::new();
+        sources.add("/a.rack", "root").add("a.rack", "relative");
+        assert_eq!(sources.get("/../a.rack"), Some("root"));
+        assert_eq!(sources.get("/lib/../../a.rack"), Some("root"));
+        assert_eq!(sources.get("lib/../a.rack"), Some("relative"));
+        assert_eq!(sources.get("../a.rack"), None);
+    }
+
+    #[test]
+    fn test_formatter() {
+        let source = "\
+.include \"lib.rack\"
+MOV 10 R1
+    start:
+PSH 0b11
+mov [0x100] acc
+add r1  acc
+
+
+ .loop:
+jne .loop r2
+1:
+  HLT
+data:
+.byte 1 2
+";
+        let formatted = Formatter::format_source(source, "main.rack").unwrap();
+        assert_eq!(
+            formatted,
+            "\
+.include \"lib.rack\"
+mov 0xA r1
+start:
+  psh 0x3
+  mov [0x100] acc
+  add r1      acc
+
+  .loop:
+    jne .loop r2
+  1:
+    hlt
+data:
+  .byte 0x1 0x2
+"
+        );
+        assert_eq!(
+            Formatter::format_source(&formatted, "main.rack").unwrap(),
+            formatted
+        );
+        let nodes = |source: &str| -> Vec<ASTNode> {
+            ASTParser::parse_source(source, "main.rack".into())
+                .unwrap()
+                .into_iter()
+                .map(|stmt| stmt.node)
+                .collect()
+        };
+        assert_eq!(nodes(&formatted), nodes(source));
+
+        assert!(Formatter::format_source("mov 1\n", "main.rack")
+            .unwrap_err()
+            .to_string()
+            .contains("main.rack:1:1"));
+    }
+
+    #[test]
+    fn test_comments_and_whitespace() {
+        let source = "; adds two numbers\r\n\
+main:\t# the entry point\r\n\
+\tmov\t5\tr1 ; five\r\n\
+\tmov 0x10 r2\r\n\
+\t/* a comment\r\n\
+\t   over two lines */\r\n\
+\tadd r1 r2 /* inline */\r\n\
+\thlt";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let comments: Vec<(&str, usize)> = ast
+            .iter()
+            .filter_map(|stmt| match &stmt.node {
+                ASTNode::Comment(text) => Some((text.as_str(), stmt.loc.line)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            comments,
+            [
+                ("; adds two numbers", 1),
+                ("# the entry point", 2),
+                ("; five", 3),
+                ("/* a comment\r\n\t   over two lines */", 5),
+                ("/* inline */", 7),
+            ]
+        );
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let cpu = CPU::new(assembly.memory);
+        while !cpu.step().unwrap() {}
+        assert_eq!(cpu.get_register(&Register::ACC), 0x15);
+
+        assert_eq!(
+            Formatter::format_source(source, "main.rack").unwrap(),
+            "\
+; adds two numbers
+main: # the entry point
+  mov 0x5  r1 ; five
+  mov 0x10 r2
+  /* a comment\r\n\t   over two lines */
+  add r1 r2 /* inline */
+  hlt
+"
+        );
+    }
+
+    #[test]
+    fn test_char_string_and_negative_literals() {
+        let source = "\
+mov 'A' r1
+mov -1 r2
+add -2 r1
+mov '\\n' r3
+jmp end
+text:
+.byte \"h\\x69\\\"\" -128 '\\0'
+.table -32768 'z'
+end:
+hlt
+";
+        let ast = ASTParser::parse_str(source).unwrap();
+        assert_eq!(
+            ast[6].node,
+            ASTNode::Byte(vec![
+                ASTArg::Str(b"hi\"".to_vec()),
+                ASTArg::Neg(128),
+                ASTArg::Char('\0'),
+            ])
+        );
+        assert_eq!(ast[6].node.to_string(), r#".byte "hi\"" -128 '\0'"#);
+        // control characters outside ASCII are written as they are, which parses back the same
+        for arg in [
+            ASTArg::Str("\u{7}\u{80}\u{9F}".as_bytes().to_vec()),
+            ASTArg::Char('\u{85}'),
+        ] {
+            let node = ASTNode::Byte(vec![arg]);
+            let reparsed = ASTParser::parse_str(&format!("{}\n", node)).unwrap();
+            assert_eq!(reparsed[0].node, node);
+        }
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let text = assembly.symbols["text"] as usize;
+        assert_eq!(
+            assembly.memory.get_buf(text, text + 9).unwrap(),
+            [b'h', b'i', b'"', 0x80, 0, 0x80, 0, 0, b'z']
+        );
+        let cpu = CPU::new(assembly.memory);
+        while !cpu.step().unwrap() {}
+        assert_eq!(cpu.get_register(&Register::R1), 0x41);
+        assert_eq!(cpu.get_register(&Register::R2), 0xFFFF);
+        assert_eq!(cpu.get_register(&Register::ACC), 0x3F);
+        assert_eq!(cpu.get_register(&Register::R3), 0x0A);
+
+        for (source, message) in [
+            (".byte -129", "Value -129 doesn't fit in 8 bits"),
+            (".byte 'λ'", "Value 'λ' doesn't fit in 8 bits"),
+            ("mov -32769 r1", "Value -32769 doesn't fit in 16 bits"),
+            (".table \"ab\"", "Invalid argument: Str([97, 98])"),
+        ] {
+            let ast = ASTParser::parse_str(source).unwrap();
+            let diagnostics = Assembler::assemble_with(ast, &AssemblerOptions::default())
+                .err()
+                .unwrap();
+            assert_eq!(diagnostics.iter().next().unwrap().message, message);
+        }
+    }
+
+    #[test]
+    fn test_labels_on_instruction_lines() {
+        let source = "\
+main: mov 3 r1
+zeta: alpha: loop: dec r1 ; count down
+  jne loop 0
+data: .byte 1
+end:
+";
+        let ast = ASTParser::parse_str(source).unwrap();
+        let nodes: Vec<String> = ast.iter().map(|stmt| stmt.node.to_string()).collect();
+        assert_eq!(
+            nodes,
+            [
+                "main:",
+                "mov 0x3 r1",
+                "zeta:",
+                "alpha:",
+                "loop:",
+                "dec r1",
+                "; count down",
+                "jne loop 0x0",
+                "data:",
+                ".byte 0x1",
+                "end:",
+            ]
+        );
+        assert_eq!((ast[5].loc.line, ast[5].loc.col), (2, 20));
+
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        assert_eq!(assembly.symbols["zeta"], 4);
+        assert_eq!(assembly.symbols["alpha"], 4);
+        assert_eq!(assembly.symbols["loop"], 4);
+        let lines = Disassembler::new(&assembly.memory)
+            .with_symbols(assembly.labels())
+            .disassemble(0..0xC);
+        let text = Disassembler::to_source(&lines);
+        assert!(
+            text.contains("zeta:\nalpha:\nloop:\n  dec r1\n"),
+            "{}",
+            text
+        );
+        assert!(text.ends_with("data:\n  .byte 0x1\n"), "{}", text);
+    }
+
+    #[test]
+    fn test_indexed_addressing() {
+        let source = "\
+mov 0x100 r1
+mov 3 r2
+mov 0xBEEF [r1 + r2*2 - 2]
+mov [0x104] r3
+mov [4 + r1] r4
+mov r2 [r2 + r1]
+mov [0x103] r5
+mov [table + 2] r6
+mov 1 r7
+mov [r7*2 + table] r8
+jmp [table + r7*2 + 2]
+hlt
+done:
+mov 1 acc
+hlt
+table:
+.table 0x1111 0x2222 done
+";
+        let ast = ASTParser::parse_str(source).unwrap();
+        assert_eq!(
+            ast[2].node,
+            ASTNode::Mov(
+                ASTArg::Lit(0xBEEF),
+                ASTArg::Indexed(Box::new(Address {
+                    base: Some(Register::R1),
+                    index: Some((Register::R2, 2)),
+                    label: None,
+                    displacement: -2,
+                }))
+            )
+        );
+        assert_eq!(ast[2].node.to_string(), "mov 0xBEEF [r1 + r2*2 - 0x2]");
+        assert_eq!(ast[4].node.to_string(), "mov [r1 + 0x4] r4");
+        assert_eq!(ast[7].node.to_string(), "mov [table + 0x2] r6");
+
+        let assembly = Assembler::assemble_with(ast, &AssemblerOptions::default()).unwrap();
+        let cpu = CPU::new(assembly.memory);
+        while !cpu.step().unwrap() {}
+        assert_eq!(cpu.get_register(&Register::R3), 0xBEEF);
+        assert_eq!(cpu.get_register(&Register::R4), 0xBEEF);
+        assert_eq!(cpu.get_register(&Register::R5), 0x03);
+        assert_eq!(cpu.get_register(&Register::R6), 0x2222);
+        assert_eq!(cpu.get_register(&Register::R8), 0x2222);
+        assert_eq!(cpu.get_register(&Register::ACC), 1);
+
+        for (source, message) in [
+            ("mov [r1 + r2 + r3] r4", "An address can use at most two registers: r3"),
+            ("mov [r1 - r2] r4", "Only numbers can be subtracted in an address: r2"),
+            ("mov [a + b] r4", "An address can only refer to one label: b"),
+            ("mov [r1 + 0xFFFF + 1] r4", "Displacement doesn't fit in 16 bits: 1"),
+        ] {
+            let error = ASTParser::parse_str(source).unwrap_err().to_string();
+            assert!(error.trim_end().ends_with(message), "{}", error);
+        }
+        let ast = ASTParser::parse_str("mov [table + 2] r1\ntable:\n").unwrap();
+        let options = AssemblerOptions {
+            pic: true,
+            ..AssemblerOptions::default()
+        };
+        assert!(Assembler::assemble_with(ast, &options).is_err());
+    }
+
+    #[test]
+    fn test_parser_error_recovery() {
+        let source = "\
+main:
+  mvo 5 r1
+  mov 5 r1 r2
+  .bytes 1
+  mov 5 [r1 +]
+  hlt
+  /* unterminated
+";
+        let diagnostics = match ASTParser::parse_str(source).unwrap_err() {
+            AssemblerError::Diagnostics(diagnostics) => diagnostics,
+            error => panic!("expected diagnostics, got {:?}", error),
+        };
+        let errors: Vec<(usize, usize, &str)> = diagnostics
+            .iter()
+            .map(|d| {
+                let loc = d.loc.as_ref().unwrap();
+                (loc.line, loc.col, d.message.as_str())
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (
+                    2,
+                    3,
+                    "Parser error: Unknown instruction: mvo, did you mean `mov`?"
+                ),
+                (3, 12, "Syntax error: expected end of line or comment"),
+                (
+                    4,
+                    3,
+                    "Parser error: Unknown directive: .bytes, did you mean `.byte`?"
+                ),
+                (5, 14, "Syntax error: expected number, register or label"),
+                (7, 3, "Unterminated block comment"),
+            ]
+        );
+
+        let ast = ASTParser::parse_str("mov 5 rr1\njmp mian\nmain:\nmov [rr2] r1\n").unwrap();
+        let diagnostics = Assembler::assemble_with(ast, &AssemblerOptions::default())
+            .err()
+            .unwrap();
+        let messages: Vec<&str> = diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Invalid argument: rr1, did you mean `r1`?",
+                "Invalid label: mian, did you mean `main`?",
+                "Invalid label: rr2, did you mean `r2`?",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_analysis() {
+        let mut sources = SourceSet::new();
+        sources.add(
+            "main.rack",
+            "main:\n  mov 3 r1\n.loop: dec r1\n  jne .loop r1\n  jmp main\n",
+        );
+        let analysis = Analysis::new(&sources, "main.rack");
+        assert_eq!(analysis.diagnostics().count(), 0);
+
+        let definition = analysis.definition(4, 8).unwrap();
+        assert_eq!(definition.name, "main.loop");
+        assert_eq!((definition.loc.line, definition.loc.col), (3, 1));
+        let references: Vec<(usize, usize)> = analysis
+            .references(3, 2)
+            .iter()
+            .map(|r| (r.loc.line, r.loc.col))
+            .collect();
+        assert_eq!(references, [(4, 7)]);
+        assert_eq!(analysis.definition(5, 7).unwrap().name, "main");
+        assert_eq!(analysis.hover(5, 7).unwrap(), "`main` at `0x0000`");
+
+        let hover = analysis.hover(2, 4).unwrap();
+        assert!(hover.starts_with("`MovLitReg`"), "{}", hover);
+        assert!(hover.ends_with("4 bytes: `10 00 03 02`"), "{}", hover);
+
+        let completions = analysis.completions(2, 3);
+        assert!(completions
+            .iter()
+            .any(|c| c.label == "mov" && c.kind == CompletionKind::Mnemonic));
+        assert!(completions.iter().all(|c| c.kind != CompletionKind::Register));
+        let completions = analysis.completions(4, 7);
+        assert!(completions
+            .iter()
+            .any(|c| c.label == "r1" && c.kind == CompletionKind::Register));
+        assert!(completions
+            .iter()
+            .any(|c| c.label == ".loop" && c.kind == CompletionKind::Label));
+
+        sources.add("main.rack", "main:\n  mvo 3 r1\n  hlt\n");
+        let analysis = Analysis::new(&sources, "main.rack");
+        let lines: Vec<usize> = analysis
+            .diagnostics()
+            .map(|d| d.loc.as_ref().unwrap().line)
+            .collect();
+        assert_eq!(lines, [2]);
+    }
 }
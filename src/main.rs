@@ -1,29 +1,254 @@
-use rustystack::{assembler::Assembler, parser::ASTParser, cpu::CPU};
-
-pub fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let parsed = ASTParser::parse_file(&args[1]);
-    match parsed {
-        Ok(ast) => {
-            println!("--------- SUCCESFULLY PARSED ---------");
-            // for node in ast {
-            // println!("{:?}", node);
-            // }
-            match Assembler::assemble(ast) {
-                Ok(mem) => {
-                    println!("--------- SUCCESFULLY ASSEMBLED ---------");
-                    let cpu = CPU::new(mem);
-                    cpu.run().unwrap();
-                }
-                Err(e) => {
-                    println!("--------- ERROR IN ASSEMBLING ---------");
-                    println!("{}", e);
-                }
+use std::{
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    net::TcpListener,
+    process::ExitCode,
+};
+
+use rustystack::{
+    assembler::{Assembler, AssemblerError, Assembly},
+    cli::{Args, Failure, Parsed, USAGE},
+    cpu::{CpuError, CPU},
+    debugger::{Debugger, Reply},
+    disassembler::Disassembler,
+    formatter::Formatter,
+    gdb::{Disconnect, GdbTarget},
+    image::Image,
+    memory::Memory,
+    parser::ASTParser,
+    register::Register,
+    trace::{TraceError, Tracer},
+};
+
+pub fn main() -> ExitCode {
+    let result = Args::parse(std::env::args().skip(1)).and_then(|parsed| match parsed {
+        Parsed::Help(help) => {
+            println!("{}", help);
+            Ok(0)
+        }
+        Parsed::Command(args) => match args.command {
+            "assemble" => assemble(&args).map(|()| 0),
+            "disasm" => disasm(&args).map(|()| 0),
+            "check" => check(&args).map(|()| 0),
+            "fmt" => fmt(&args).map(|()| 0),
+            "syscalls" => syscalls(&args).map(|()| 0),
+            // a program stopped before it halts has nothing to report
+            "debug" => debug(&args),
+            "gdb" => gdb(&args),
+            _ => run(&args),
+        },
+    });
+    match result {
+        Ok(status) => ExitCode::from(status),
+        Err(Failure::Usage(message)) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            ExitCode::from(2)
+        }
+        Err(Failure::Error(message)) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+        Err(Failure::Reported) => ExitCode::FAILURE,
+    }
+}
+
+/// Prints an error that carries its own location, like the diagnostics of a file that doesn't parse.
+fn report(error: AssemblerError) -> Failure {
+    eprintln!("{}", error.to_string().trim_end());
+    Failure::Reported
+}
+
+/// Reads the file the command is given.
+fn read(args: &Args) -> Result<Vec<u8>, Failure> {
+    std::fs::read(&args.file)
+        .map_err(|e| Failure::Error(format!("could not read {}: {}", args.file, e)))
+}
+
+/// Parses and assembles the source file, reporting the diagnostics on stderr.
+fn assemble_source(args: &Args) -> Result<Assembly, Failure> {
+    let ast = ASTParser::parse_file_with(&args.file, &args.include_paths).map_err(report)?;
+    match Assembler::assemble_with(ast, &args.options) {
+        Ok(assembly) => {
+            eprint!("{}", assembly.diagnostics);
+            Ok(assembly)
+        }
+        Err(diagnostics) => {
+            eprint!("{}", diagnostics);
+            Err(Failure::Reported)
+        }
+    }
+}
+
+fn assemble(args: &Args) -> Result<(), Failure> {
+    let output = args.output.as_deref().unwrap_or_default();
+    let assembly = assemble_source(args)?;
+    std::fs::write(output, assembly.to_image().to_bytes())
+        .map_err(|e| Failure::Error(format!("could not write {}: {}", output, e)))
+}
+
+fn check(args: &Args) -> Result<(), Failure> {
+    assemble_source(args).map(|_| ())
+}
+
+/// Reads an image file, failing if it's malformed.
+fn read_image(args: &Args, bytes: &[u8]) -> Result<Image, Failure> {
+    Image::from_bytes(bytes).map_err(|e| Failure::Error(format!("{}: {}", args.file, e)))
+}
+
+/// Prints the source of an image or, using its labels, of an assembled source file.
+fn disasm(args: &Args) -> Result<(), Failure> {
+    let bytes = read(args)?;
+    let source = if Image::is_image(&bytes) {
+        let image = read_image(args, &bytes)?;
+        let memory = Memory::default();
+        image
+            .load_into(&memory)
+            .map_err(|e| Failure::Error(format!("{}: {}", args.file, e)))?;
+        Disassembler::to_source(&Disassembler::new(&memory).disassemble_image(&image))
+    } else {
+        let assembly = assemble_source(args)?;
+        let disassembler = Disassembler::new(&assembly.memory).with_symbols(assembly.labels());
+        Disassembler::to_source(&disassembler.disassemble_image(&assembly.to_image()))
+    };
+    print!("{}", source);
+    Ok(())
+}
+
+/// Rewrites the file in canonical form or, with `--check`, fails if it isn't in canonical form.
+fn fmt(args: &Args) -> Result<(), Failure> {
+    let source = String::from_utf8(read(args)?)
+        .map_err(|_| Failure::Error(format!("{} is not valid UTF-8", args.file)))?;
+    let formatted = Formatter::format_source(&source, &args.file).map_err(report)?;
+    if formatted == source {
+        return Ok(());
+    }
+    if args.check {
+        return Err(Failure::Error(format!("{} is not formatted", args.file)));
+    }
+    std::fs::write(&args.file, formatted)
+        .map_err(|e| Failure::Error(format!("could not write {}: {}", args.file, e)))
+}
+
+/// Lists the syscalls programs can call, by number and name.
+fn syscalls(args: &Args) -> Result<(), Failure> {
+    for syscall in args.options.syscalls.iter() {
+        println!(
+            "0x{:02X} {:<12} {}",
+            syscall.number, syscall.name, syscall.description
+        );
+    }
+    Ok(())
+}
+
+/// Loads either an assembled image or a source file into a new CPU, telling them apart by the
+/// image magic. Returns the image with the labels of the source, if any.
+fn load(args: &Args, cpu: &CPU) -> Result<(Image, Vec<(String, u16)>), Failure> {
+    let bytes = read(args)?;
+    let (image, symbols) = if Image::is_image(&bytes) {
+        (read_image(args, &bytes)?, vec![])
+    } else {
+        let assembly = assemble_source(args)?;
+        let symbols = assembly
+            .labels()
+            .map(|(name, addr)| (name.clone(), *addr))
+            .collect();
+        (assembly.to_image(), symbols)
+    };
+    cpu.load_image(&image)
+        .map_err(|e| Failure::Error(format!("{}: {}", args.file, e)))?;
+    Ok((image, symbols))
+}
+
+/// Makes the exit status of a program that of the process, with those that don't fit in a byte
+/// made 255.
+fn exit_status(status: u16) -> u8 {
+    u8::try_from(status).unwrap_or(u8::MAX)
+}
+
+/// Runs the program, returning its exit status.
+fn run(args: &Args) -> Result<u8, Failure> {
+    let cpu = CPU::new(Memory::default());
+    let (_, symbols) = load(args, &cpu)?;
+    let status = match &args.trace {
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| Failure::Error(format!("could not write {}: {}", path, e)))?;
+            let mut tracer = Tracer::new(&cpu, BufWriter::new(file))
+                .with_symbols(symbols.iter().map(|(name, addr)| (name, addr)));
+            for filter in &args.trace_filters {
+                tracer
+                    .filter(filter)
+                    .map_err(|e| Failure::Error(e.to_string()))?;
             }
+            tracer.run().map_err(|e| match e {
+                TraceError::Cpu(e) => runtime_error(args, &cpu, e),
+                e => Failure::Error(e.to_string()),
+            })?
         }
-        Err(e) => {
-            println!("--------- ERROR IN PARSING ---------");
-            println!("{}", e);
+        None => cpu.run().map_err(|e| runtime_error(args, &cpu, e))?,
+    };
+    Ok(exit_status(status))
+}
+
+fn runtime_error(args: &Args, cpu: &CPU, e: CpuError) -> Failure {
+    Failure::Error(format!(
+        "{}: {} at ip 0x{:04X}",
+        args.file,
+        e,
+        cpu.get_register(&Register::IP)
+    ))
+}
+
+/// Runs the program under the debugger, reading commands from stdin until it ends or `quit`.
+/// Returns the exit status of the program, or 0 if it didn't halt.
+fn debug(args: &Args) -> Result<u8, Failure> {
+    let cpu = CPU::new(Memory::default());
+    let (image, symbols) = load(args, &cpu)?;
+    let mut debugger =
+        Debugger::new(&cpu, &image).with_symbols(symbols.iter().map(|(name, addr)| (name, addr)));
+    println!("{}", debugger.location());
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!("(rack) ");
+        let _ = std::io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => return Err(Failure::Error(format!("could not read stdin: {}", e))),
+            None => {
+                println!();
+                break;
+            }
+        };
+        match debugger.execute(&line) {
+            Ok(Reply::Output(output)) if output.is_empty() => (),
+            Ok(Reply::Output(output)) => println!("{}", output),
+            Ok(Reply::Quit) => break,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    Ok(cpu.exit_status().map_or(0, exit_status))
+}
+
+/// Runs the program under a debugger connecting over TCP. Returns the exit status of the
+/// program, which is run to completion if the debugger detaches, or 0 if it's killed.
+fn gdb(args: &Args) -> Result<u8, Failure> {
+    let cpu = CPU::new(Memory::default());
+    load(args, &cpu)?;
+    let address = format!("127.0.0.1:{}", args.port);
+    let listener = TcpListener::bind(&address)
+        .map_err(|e| Failure::Error(format!("could not listen on {}: {}", address, e)))?;
+    eprintln!("Waiting for a debugger on {}", address);
+    let (stream, peer) = listener
+        .accept()
+        .map_err(|e| Failure::Error(format!("could not accept a debugger: {}", e)))?;
+    eprintln!("Debugger connected from {}", peer);
+    match GdbTarget::new(&cpu).serve(stream) {
+        Ok(Disconnect::Exited(status)) => Ok(status),
+        Ok(Disconnect::Killed) => Ok(0),
+        Ok(Disconnect::Detached) => {
+            let status = cpu.run().map_err(|e| runtime_error(args, &cpu, e))?;
+            Ok(exit_status(status))
         }
+        Err(e) => Err(Failure::Error(format!("debugging session failed: {}", e))),
     }
 }
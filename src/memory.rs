@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, ops::Range};
 
 use crate::cpu::CpuError;
 
@@ -8,7 +8,7 @@ pub struct Memory {
     memory: RefCell<Vec<u8>>,
 }
 
-pub(crate) trait InspectableAddr {
+pub trait InspectableAddr {
     type Error;
     /// Inspects a place in memory at the given address, returns 8 bytes of data starting from that
     /// place.
@@ -84,15 +84,22 @@ impl InspectableAddr for Memory {
 pub struct MemoryBuilder {
     memory: Memory,
     counter: usize,
+    /// The ranges of cells that were written or reserved so far
+    used: Vec<Range<usize>>,
 }
 
 impl MemoryBuilder {
     pub fn new(memory: Memory) -> MemoryBuilder {
-        MemoryBuilder { memory, counter: 0 }
+        MemoryBuilder {
+            memory,
+            counter: 0,
+            used: vec![],
+        }
     }
 
     pub fn push(&mut self, value: u8) -> usize {
         self.memory.set(self.counter, value);
+        self.mark_used(self.counter);
         self.counter += 1;
         self.counter
     }
@@ -104,11 +111,24 @@ impl MemoryBuilder {
         self.counter
     }
 
+    /// Gets the size of the memory buffer being built.
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    #[must_use]
+    /// Determines if the memory buffer being built is empty or not.
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
     pub fn get_counter(&self) -> usize {
         self.counter
     }
 
+    /// Reserves the cell at the counter without writing to it, then increments the counter.
     pub fn incr(&mut self) {
+        self.mark_used(self.counter);
         self.counter += 1;
     }
 
@@ -116,6 +136,28 @@ impl MemoryBuilder {
         self.counter = counter;
     }
 
+    fn mark_used(&mut self, index: usize) {
+        match self.used.last_mut() {
+            Some(last) if last.end == index => last.end += 1,
+            Some(last) if last.contains(&index) => (),
+            _ => self.used.push(index..index + 1),
+        }
+    }
+
+    /// Gets the sorted, non overlapping ranges of cells that were written or reserved so far.
+    pub fn used_ranges(&self) -> Vec<Range<usize>> {
+        let mut sorted = self.used.clone();
+        sorted.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = vec![];
+        for range in sorted {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
     pub fn build(self) -> Memory {
         self.memory
     }
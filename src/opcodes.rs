@@ -1,211 +1,427 @@
-/// Represents an opcode that is executable by the CPU.
-pub enum OpCode {
+use crate::{
+    ast::{ASTArg, Address},
+    cpu::CpuError,
+    register::Register,
+    to_u16,
+};
+
+/// Defines [`OpCode`] together with [`INSTRUCTIONS`], so that every opcode has exactly one entry
+/// describing how it is written in source and laid out in memory.
+///
+/// Each entry reads `Name = byte, "mnemonic", [operand kinds in source order]`, optionally
+/// followed by `=> [encoding order]` when the operands are encoded in a different order than
+/// they're written.
+macro_rules! instructions {
+    ($(
+        $(#[doc = $doc:expr])*
+        $name:ident = $byte:expr, $mnemonic:expr, [$($kind:ident),*] $(=> [$($field:expr),*])?;
+    )*) => {
+        /// Represents an opcode that is executable by the CPU.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        pub enum OpCode {
+            $($(#[doc = $doc])* $name,)*
+        }
+
+        /// The table describing every instruction, indexed by [`OpCode`].
+        pub const INSTRUCTIONS: &[InstructionDef] = &[
+            $(InstructionDef {
+                opcode: OpCode::$name,
+                byte: $byte,
+                mnemonic: $mnemonic,
+                operands: &[$(OperandKind::$kind),*],
+                layout: &[$($($field),*)?],
+            },)*
+        ];
+
+        impl TryFrom<u8> for OpCode {
+            type Error = CpuError;
+
+            fn try_from(value: u8) -> Result<Self, Self::Error> {
+                match value {
+                    $($byte => Ok(OpCode::$name),)*
+                    _ => Err(CpuError::InvalidInstruction),
+                }
+            }
+        }
+    };
+}
+
+instructions! {
     /// No operation
-    Nop,
+    Nop = 0x00, "nop", [];
     /// Moves the literal value into the register
-    MovLitReg,
+    MovLitReg = 0x10, "mov", [Lit, Reg];
     /// Moves the value in the register into the register
-    MovRegReg,
+    MovRegReg = 0x11, "mov", [Reg, Reg];
     /// Moves the value in the register into the memory location
-    MovRegMem,
+    MovRegMem = 0x12, "mov", [Reg, Mem];
     /// Moves the value in the memory location into the register
-    MovMemReg,
+    MovMemReg = 0x13, "mov", [Mem, Reg];
     /// Adds two registers together, pointed by the ip register and stores the result in the acc register
-    AddRegReg,
+    AddRegReg = 0x14, "add", [Reg, Reg];
     /// Jumps to the given address if the acc register is not equal to the given value
-    JmpNELit,
+    JmpNELit = 0x15, "jne", [Addr, Lit] => [1, 0];
     /// Pushes the value of the given register onto the stack
-    PshReg,
+    PshReg = 0x16, "psh", [Reg];
     /// Pushes the value of the given literal onto the stack
-    PshLit,
+    PshLit = 0x17, "psh", [Lit];
     /// Pops the value off the stack and stores it in the given register
-    Pop,
+    Pop = 0x18, "pop", [Reg];
     /// Calls the given literal address
-    CalLit,
+    CalLit = 0x19, "cal", [Addr];
     /// Calls the given register address
-    CalReg,
+    CalReg = 0x1A, "cal", [Reg];
     /// Returns from the current function
-    Ret,
+    Ret = 0x1B, "ret", [];
     /// Halts the CPU
-    Hlt,
+    Hlt = 0x1C, "hlt", [];
     /// Moves the literal value into the memory location
-    MovLitMem,
-    /// Moves the pointer register to the given register
-    MovRegPtrReg,
+    MovLitMem = 0x1D, "mov", [Lit, Mem];
+    /// Moves the value in the memory location pointed by the register into the given register
+    MovRegPtrReg = 0x1E, "mov", [RegPtr, Reg];
+    /// Moves the value in the register into the memory location pointed by the given register
+    MovRegRegPtr = 0x1F, "mov", [Reg, RegPtr];
     /// Adds the given literal with the given register and stores the result in the acc register
-    AddLitReg,
-    /// Subtracts the given literal with the given register and stores the result in the acc register
-    SubLitReg,
-    /// Subtracts the given register with the given literal and stores the result in the acc register
-    SubRegLit,
-    /// Subtracts the given register with the given register and stores the result in the acc register
-    SubRegReg,
+    AddLitReg = 0x20, "add", [Lit, Reg];
+    /// Subtracts the given literal from the given register and stores the result in the acc register
+    SubLitReg = 0x21, "sub", [Lit, Reg];
+    /// Subtracts the given register from the given literal and stores the result in the acc register
+    SubRegLit = 0x22, "sub", [Reg, Lit];
+    /// Subtracts the first register from the second and stores the result in the acc register
+    SubRegReg = 0x23, "sub", [Reg, Reg];
     /// Multiplies the given literal with the given register and stores the result in the acc register
-    MulLitReg,
+    MulLitReg = 0x24, "mul", [Lit, Reg];
     /// Multiplies the given register with the given register and stores the result in the acc register
-    MulRegReg,
-    /// Increments the given register by 1 in palce
-    IncReg,
-    /// Decrements the given register by 1 in palce
-    DecReg,
-    /// Shifts the given register left by the given amount in palce
-    ShlRegLit,
+    MulRegReg = 0x25, "mul", [Reg, Reg];
+    /// Increments the given register by 1 in place
+    IncReg = 0x26, "inc", [Reg];
+    /// Decrements the given register by 1 in place
+    DecReg = 0x27, "dec", [Reg];
+    /// Shifts the given register left by the given amount in place
+    ShlRegLit = 0x28, "shl", [Reg, Lit];
     /// Shifts the given register left by the given register in place
-    ShlRegReg,
+    ShlRegReg = 0x29, "shl", [Reg, Reg];
     /// Shifts the given register right by the given amount in place
-    ShrRegLit,
+    ShrRegLit = 0x2A, "shr", [Reg, Lit];
     /// Shifts the given register right by the given register in place
-    ShrRegReg,
+    ShrRegReg = 0x2B, "shr", [Reg, Reg];
     /// Bitwise ANDs the given register with the given literal and stores the result in the acc register
-    AndRegLit,
+    AndRegLit = 0x2C, "and", [Reg, Lit];
     /// Bitwise ANDs the given register with the given register and stores the result in the acc register
-    AndRegReg,
+    AndRegReg = 0x2D, "and", [Reg, Reg];
     /// Bitwise ORs the given register with the given literal and stores the result in the acc register
-    OrRegLit,
+    OrRegLit = 0x2E, "or", [Reg, Lit];
     /// Bitwise ORs the given register with the given register and stores the result in the acc register
-    OrRegReg,
+    OrRegReg = 0x2F, "or", [Reg, Reg];
     /// Bitwise XORs the given register with the given literal and stores the result in the acc register
-    XorRegLit,
+    XorRegLit = 0x30, "xor", [Reg, Lit];
     /// Bitwise XORs the given register with the given register and stores the result in the acc register
-    XorRegReg,
+    XorRegReg = 0x31, "xor", [Reg, Reg];
     /// Bitwise NOTs the given register and stores the result in the acc register
-    NotReg,
+    NotReg = 0x32, "not", [Reg];
     /// Jumps to the given address if the acc register is not equal to the given register
-    JmpNEReg,
+    JmpNEReg = 0x33, "jne", [Addr, Reg] => [1, 0];
     /// Jumps to the given address if the acc register is equal to the given literal
-    JmpEQLit,
+    JmpEQLit = 0x34, "jeq", [Addr, Lit] => [1, 0];
     /// Jumps to the given address if the acc register is equal to the given register
-    JmpEQReg,
+    JmpEQReg = 0x35, "jeq", [Addr, Reg] => [1, 0];
     /// Jumps to the given address if the given literal is less than the acc register
-    JmpLTLit,
+    JmpLTLit = 0x36, "jlt", [Addr, Lit] => [1, 0];
     /// Jumps to the given address if the given register is less than the acc register
-    JmpLTReg,
+    JmpLTReg = 0x37, "jlt", [Addr, Reg] => [1, 0];
     /// Jumps to the given address if the given literal is greater than the acc register
-    JmpGTLit,
+    JmpGTLit = 0x38, "jgt", [Addr, Lit] => [1, 0];
     /// Jumps to the given address if the given register is greater than the acc register
-    JmpGTReg,
+    JmpGTReg = 0x39, "jgt", [Addr, Reg] => [1, 0];
     /// Jumps to the given address if the given literal is less than or equal to the acc register
-    JmpLELit,
+    JmpLELit = 0x3A, "jle", [Addr, Lit] => [1, 0];
     /// Jumps to the given address if the given register is less than or equal to the acc register
-    JmpLEReg,
+    JmpLEReg = 0x3B, "jle", [Addr, Reg] => [1, 0];
     /// Jumps to the given address if the given literal is greater than or equal to the acc register
-    JmpGELit,
+    JmpGELit = 0x3C, "jge", [Addr, Lit] => [1, 0];
     /// Jumps to the given address if the given register is greater than or equal to the acc register
-    JmpGEReg,
+    JmpGEReg = 0x3D, "jge", [Addr, Reg] => [1, 0];
     /// Jumps to the given address
-    Jmp,
-    /// System call, value retrieved from the ACC register, used to call a function in the VM
-    SysLit,
+    Jmp = 0x3E, "jmp", [Addr];
+    /// System call, calls the function of the VM with the given number
+    SysLit = 0x3F, "sys", [Byte];
+    /// Jumps to the address in the given register
+    JmpReg = 0x40, "jmp", [Reg];
+    /// Jumps to the address stored at the given memory location
+    JmpMem = 0x41, "jmp", [Mem];
+    /// Jumps to the address stored at the memory location pointed by the given register
+    JmpRegPtr = 0x42, "jmp", [RegPtr];
+    /// Jumps to the given relative address
+    JmpRel = 0x43, "jmp", [Rel];
+    /// Calls the given relative address
+    CalRel = 0x44, "cal", [Rel];
+    /// Jumps to the given relative address if the given literal is not equal to the acc register
+    JmpNELitRel = 0x45, "jne", [Rel, Lit] => [1, 0];
+    /// Jumps to the given relative address if the given register is not equal to the acc register
+    JmpNERegRel = 0x46, "jne", [Rel, Reg] => [1, 0];
+    /// Jumps to the given relative address if the given literal is equal to the acc register
+    JmpEQLitRel = 0x47, "jeq", [Rel, Lit] => [1, 0];
+    /// Jumps to the given relative address if the given register is equal to the acc register
+    JmpEQRegRel = 0x48, "jeq", [Rel, Reg] => [1, 0];
+    /// Jumps to the given relative address if the given literal is less than the acc register
+    JmpLTLitRel = 0x49, "jlt", [Rel, Lit] => [1, 0];
+    /// Jumps to the given relative address if the given register is less than the acc register
+    JmpLTRegRel = 0x4A, "jlt", [Rel, Reg] => [1, 0];
+    /// Jumps to the given relative address if the given literal is greater than the acc register
+    JmpGTLitRel = 0x4B, "jgt", [Rel, Lit] => [1, 0];
+    /// Jumps to the given relative address if the given register is greater than the acc register
+    JmpGTRegRel = 0x4C, "jgt", [Rel, Reg] => [1, 0];
+    /// Jumps to the given relative address if the given literal is less than or equal to the acc
+    /// register
+    JmpLELitRel = 0x4D, "jle", [Rel, Lit] => [1, 0];
+    /// Jumps to the given relative address if the given register is less than or equal to the
+    /// acc register
+    JmpLERegRel = 0x4E, "jle", [Rel, Reg] => [1, 0];
+    /// Jumps to the given relative address if the given literal is greater than or equal to the
+    /// acc register
+    JmpGELitRel = 0x4F, "jge", [Rel, Lit] => [1, 0];
+    /// Jumps to the given relative address if the given register is greater than or equal to the
+    /// acc register
+    JmpGERegRel = 0x50, "jge", [Rel, Reg] => [1, 0];
+    /// Moves the given relative address into the given register
+    MovRelReg = 0x51, "mov", [Rel, Reg];
+    /// Moves the value in the computed memory location into the given register
+    MovIdxReg = 0x52, "mov", [Indexed, Reg];
+    /// Moves the value in the given register into the computed memory location
+    MovRegIdx = 0x53, "mov", [Reg, Indexed];
+    /// Moves the literal value into the computed memory location
+    MovLitIdx = 0x54, "mov", [Lit, Indexed];
+    /// Jumps to the address stored at the computed memory location
+    JmpIdx = 0x55, "jmp", [Indexed];
 }
 
-impl From<OpCode> for u8 {
-    fn from(op: OpCode) -> Self {
-        use OpCode::*;
-        match op {
-            MovLitReg => 0x10,
-            MovRegReg => 0x11,
-            MovRegMem => 0x12,
-            MovMemReg => 0x13,
-            AddRegReg => 0x14,
-            JmpNELit => 0x15,
-            PshReg => 0x16,
-            PshLit => 0x17,
-            Pop => 0x18,
-            CalLit => 0x19,
-            CalReg => 0x1A,
-            Ret => 0x1B,
-            Hlt => 0x1C,
-            MovLitMem => 0x1D,
-            MovRegPtrReg => 0x1E,
-            AddLitReg => 0x20,
-            SubLitReg => 0x21,
-            SubRegLit => 0x22,
-            SubRegReg => 0x23,
-            MulLitReg => 0x24,
-            MulRegReg => 0x25,
-            IncReg => 0x26,
-            DecReg => 0x27,
-            ShlRegLit => 0x28,
-            ShlRegReg => 0x29,
-            ShrRegLit => 0x2A,
-            ShrRegReg => 0x2B,
-            AndRegLit => 0x2C,
-            AndRegReg => 0x2D,
-            OrRegLit => 0x2E,
-            OrRegReg => 0x2F,
-            XorRegLit => 0x30,
-            XorRegReg => 0x31,
-            NotReg => 0x32,
-            JmpNEReg => 0x33,
-            JmpEQLit => 0x34,
-            JmpEQReg => 0x35,
-            JmpLTLit => 0x36,
-            JmpLTReg => 0x37,
-            JmpGTLit => 0x38,
-            JmpGTReg => 0x39,
-            JmpLELit => 0x3A,
-            JmpLEReg => 0x3B,
-            JmpGELit => 0x3C,
-            JmpGEReg => 0x3D,
-            Jmp => 0x3E,
-            SysLit => 0x3F,
-            Nop => 0x00,
+/// The kind of an operand, which determines how it's written in source and how it's encoded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OperandKind {
+    /// A register, encoded as its index in one byte
+    Reg,
+    /// A 16 bit literal, written as a number or a label standing for its address and encoded in
+    /// two bytes
+    Lit,
+    /// An 8 bit literal, encoded in one byte
+    Byte,
+    /// The address of a jump or call, written as a label or a literal and encoded in two bytes
+    Addr,
+    /// A memory location `[addr]`, written as a label or a literal and encoded in two bytes
+    Mem,
+    /// A memory location pointed by a register `[reg]`, encoded as the register index
+    RegPtr,
+    /// An address written as a label and encoded in two bytes as its signed distance from the
+    /// end of the instruction
+    Rel,
+    /// A computed memory location such as `[r1 + r2*2 - 4]`, encoded in four bytes: the base and
+    /// index registers in the high and low nibbles of the first one, `0xF` standing for none,
+    /// then the scale and the 16 bit displacement
+    Indexed,
+}
+
+impl OperandKind {
+    /// Gets the number of bytes the operand takes in memory.
+    pub const fn size(&self) -> usize {
+        match self {
+            OperandKind::Reg | OperandKind::Byte | OperandKind::RegPtr => 1,
+            OperandKind::Lit | OperandKind::Addr | OperandKind::Mem | OperandKind::Rel => 2,
+            OperandKind::Indexed => 4,
+        }
+    }
+
+    /// Determines if the source argument can be encoded as an operand of this kind.
+    pub fn accepts(&self, arg: &ASTArg) -> bool {
+        match (self, arg) {
+            (OperandKind::Reg, ASTArg::Reg(_)) => true,
+            (OperandKind::Byte, ASTArg::Lit(_) | ASTArg::Neg(_) | ASTArg::Char(_)) => true,
+            (OperandKind::Lit, ASTArg::Neg(_) | ASTArg::Char(_)) => true,
+            (OperandKind::Lit | OperandKind::Addr, ASTArg::Lit(_) | ASTArg::Label(_)) => true,
+            (OperandKind::Mem, ASTArg::Mem(inner)) => {
+                matches!(**inner, ASTArg::Lit(_) | ASTArg::Label(_))
+            }
+            (OperandKind::RegPtr, ASTArg::Mem(inner)) => matches!(**inner, ASTArg::Reg(_)),
+            (OperandKind::Rel, ASTArg::Label(_) | ASTArg::Rel(_)) => true,
+            (OperandKind::Indexed, ASTArg::Indexed(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Converts a decoded operand of this kind back into its source form.
+    pub fn to_arg(&self, operand: Operand) -> ASTArg {
+        match (self, operand) {
+            (OperandKind::Mem | OperandKind::RegPtr, operand) => {
+                ASTArg::Mem(Box::new(OperandKind::Reg.to_arg(operand)))
+            }
+            (_, Operand::Reg(reg)) => ASTArg::Reg(reg),
+            (_, Operand::Value(value)) => ASTArg::Lit(value),
+            (_, Operand::Indexed(address)) => ASTArg::Indexed(Box::new(Address {
+                base: address.base,
+                index: address.index.map(|reg| (reg, address.scale)),
+                label: None,
+                displacement: address.displacement as i16 as i32,
+            })),
+        }
+    }
+}
+
+/// An operand decoded from memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operand {
+    /// A register, for [`OperandKind::Reg`] and [`OperandKind::RegPtr`] operands
+    Reg(Register),
+    /// The value of any other operand
+    Value(u16),
+    /// A computed memory location, for [`OperandKind::Indexed`] operands
+    Indexed(IndexedOperand),
+}
+
+/// The parts of a decoded [`OperandKind::Indexed`] operand.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IndexedOperand {
+    pub base: Option<Register>,
+    pub index: Option<Register>,
+    pub scale: u8,
+    pub displacement: u16,
+}
+
+impl IndexedOperand {
+    /// The nibble standing for a missing register.
+    const NO_REGISTER: u8 = 0xF;
+
+    /// Computes the memory location from the values of the registers.
+    pub fn address(&self, register: impl Fn(&Register) -> u16) -> u16 {
+        let base = self.base.map_or(0, |reg| register(&reg));
+        let index = self.index.map_or(0, |reg| register(&reg));
+        base.wrapping_add(index.wrapping_mul(self.scale as u16))
+            .wrapping_add(self.displacement)
+    }
+
+    fn encode(&self) -> [u8; 4] {
+        let nibble =
+            |reg: Option<Register>| reg.map_or(Self::NO_REGISTER, |reg| reg.to_index() as u8);
+        let [high, low] = self.displacement.to_be_bytes();
+        [
+            nibble(self.base) << 4 | nibble(self.index),
+            self.scale,
+            high,
+            low,
+        ]
+    }
+
+    fn decode(bytes: &[u8]) -> Result<IndexedOperand, CpuError> {
+        let register = |nibble: u8| match nibble {
+            Self::NO_REGISTER => Ok(None),
+            index => Register::from_index(index as usize)
+                .map(Some)
+                .ok_or_else(|| CpuError::InvalidRegister(index.to_string())),
+        };
+        if !matches!(bytes[1], 1 | 2 | 4) {
+            return Err(CpuError::InvalidInstruction);
         }
+        Ok(IndexedOperand {
+            base: register(bytes[0] >> 4)?,
+            index: register(bytes[0] & 0xF)?,
+            scale: bytes[1],
+            displacement: to_u16(&bytes[2..4]),
+        })
     }
 }
 
-impl From<u8> for OpCode {
-    fn from(value: u8) -> Self {
-        use OpCode::*;
-        match value {
-            0x10 => MovLitReg,
-            0x11 => MovRegReg,
-            0x12 => MovRegMem,
-            0x13 => MovMemReg,
-            0x14 => AddRegReg,
-            0x15 => JmpNELit,
-            0x16 => PshReg,
-            0x17 => PshLit,
-            0x18 => Pop,
-            0x19 => CalLit,
-            0x1A => CalReg,
-            0x1B => Ret,
-            0x1C => Hlt,
-            0x1D => MovLitMem,
-            0x1E => MovRegPtrReg,
-            0x20 => AddLitReg,
-            0x21 => SubLitReg,
-            0x22 => SubRegLit,
-            0x23 => SubRegReg,
-            0x24 => MulLitReg,
-            0x25 => MulRegReg,
-            0x26 => IncReg,
-            0x27 => DecReg,
-            0x28 => ShlRegLit,
-            0x29 => ShlRegReg,
-            0x2A => ShrRegLit,
-            0x2B => ShrRegReg,
-            0x2C => AndRegLit,
-            0x2D => AndRegReg,
-            0x2E => OrRegLit,
-            0x2F => OrRegReg,
-            0x30 => XorRegLit,
-            0x31 => XorRegReg,
-            0x32 => NotReg,
-            0x33 => JmpNEReg,
-            0x34 => JmpEQLit,
-            0x35 => JmpEQReg,
-            0x36 => JmpLTLit,
-            0x37 => JmpLTReg,
-            0x38 => JmpGTLit,
-            0x39 => JmpGTReg,
-            0x3A => JmpLELit,
-            0x3B => JmpLEReg,
-            0x3C => JmpGELit,
-            0x3D => JmpGEReg,
-            0x3E => Jmp,
-            0x3F => SysLit,
-            _ => Nop,
+/// Describes how an instruction is written in source and laid out in memory. An instruction is
+/// its opcode byte, followed by its operands in encoding order.
+#[derive(Debug)]
+pub struct InstructionDef {
+    pub opcode: OpCode,
+    /// The byte the opcode is encoded as
+    pub byte: u8,
+    pub mnemonic: &'static str,
+    /// The kinds of the operands, in the order they're written in source
+    pub operands: &'static [OperandKind],
+    /// The order the operands are encoded in, as indices into `operands`. Empty when they're
+    /// encoded in source order.
+    pub layout: &'static [usize],
+}
+
+impl InstructionDef {
+    /// Gets the indices of the operands, in the order they're encoded in.
+    pub fn encoding_order(&self) -> Vec<usize> {
+        match self.layout {
+            [] => (0..self.operands.len()).collect(),
+            layout => layout.to_vec(),
+        }
+    }
+
+    /// Gets the total number of bytes the instruction takes in memory, opcode included.
+    pub fn size(&self) -> usize {
+        1 + self.operands.iter().map(OperandKind::size).sum::<usize>()
+    }
+
+    /// Gets the offset from the start of the instruction at which the given operand is encoded.
+    pub fn operand_offset(&self, operand: usize) -> usize {
+        1 + self
+            .encoding_order()
+            .into_iter()
+            .take_while(|i| *i != operand)
+            .map(|i| self.operands[i].size())
+            .sum::<usize>()
+    }
+
+    /// Decodes the operands from the bytes following the opcode, returning them in source order.
+    pub fn decode_operands(&self, bytes: &[u8]) -> Result<Vec<Operand>, CpuError> {
+        if bytes.len() < self.size() - 1 {
+            return Err(CpuError::InvalidInstruction);
+        }
+        let mut operands = vec![Operand::Value(0); self.operands.len()];
+        for i in self.encoding_order() {
+            let at = self.operand_offset(i) - 1;
+            operands[i] = match self.operands[i] {
+                OperandKind::Reg | OperandKind::RegPtr => {
+                    let reg = Register::from_index(bytes[at] as usize)
+                        .ok_or_else(|| CpuError::InvalidRegister(bytes[at].to_string()))?;
+                    Operand::Reg(reg)
+                }
+                OperandKind::Byte => Operand::Value(bytes[at] as u16),
+                OperandKind::Lit | OperandKind::Addr | OperandKind::Mem | OperandKind::Rel => {
+                    Operand::Value(to_u16(&bytes[at..at + 2]))
+                }
+                OperandKind::Indexed => Operand::Indexed(IndexedOperand::decode(&bytes[at..])?),
+            };
         }
+        Ok(operands)
     }
+
+    /// Encodes the instruction with the given operands, which are in source order.
+    pub fn encode(&self, operands: &[Operand]) -> Vec<u8> {
+        let mut bytes = vec![self.byte];
+        for i in self.encoding_order() {
+            match (self.operands[i].size(), operands[i]) {
+                (_, Operand::Reg(reg)) => bytes.push(reg.to_index() as u8),
+                (_, Operand::Indexed(address)) => bytes.extend(address.encode()),
+                (1, Operand::Value(value)) => bytes.push(value as u8),
+                (_, Operand::Value(value)) => bytes.extend(value.to_be_bytes()),
+            }
+        }
+        bytes
+    }
+}
+
+impl OpCode {
+    /// Gets the entry of the instruction table describing the opcode.
+    pub fn def(&self) -> &'static InstructionDef {
+        &INSTRUCTIONS[*self as usize]
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(op: OpCode) -> Self {
+        op.def().byte
+    }
+}
+
+/// Gets every instruction written with the given mnemonic.
+pub fn by_mnemonic(mnemonic: &str) -> impl Iterator<Item = &'static InstructionDef> + '_ {
+    INSTRUCTIONS
+        .iter()
+        .filter(move |def| def.mnemonic.eq_ignore_ascii_case(mnemonic))
 }
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use crate::{
+    ast::{ASTArg, ASTNode, Statement},
+    diagnostics::Diagnostics,
+    register::Register,
+};
+
+/// How much the optimizer is allowed to rewrite the program.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Leave the program as it is
+    #[default]
+    O0,
+    /// Remove redundant moves and unreachable code, and turn `psh`/`pop` pairs into moves
+    O1,
+    /// Also fold arithmetic on values known at assembly time
+    O2,
+}
+
+/// A peephole optimizer working on the AST, before it's assembled.
+///
+/// Labels may be jumped to from anywhere, so the optimizer never looks across them. Removing
+/// instructions moves the code after them, so programs that jump to literal addresses instead
+/// of labels should not be optimized.
+pub struct Optimizer;
+
+impl Optimizer {
+    /// Optimizes the program, reporting every change made as a note at the location of the
+    /// statement it affected. Passes are repeated until none of them changes anything.
+    pub fn optimize(
+        mut input: Vec<Statement>,
+        level: OptLevel,
+        diagnostics: &mut Diagnostics,
+    ) -> Vec<Statement> {
+        if level == OptLevel::O0 {
+            return input;
+        }
+        loop {
+            let mut changed = Self::collapse_push_pop(&mut input, diagnostics);
+            changed |= Self::remove_redundant_moves(&mut input, diagnostics);
+            if level >= OptLevel::O2 {
+                changed |= Self::fold_constants(&mut input, diagnostics);
+            }
+            changed |= Self::remove_unreachable(&mut input, diagnostics);
+            if !changed {
+                return input;
+            }
+        }
+    }
+
+    /// Turns `psh x` directly followed by `pop reg` into `mov x reg`.
+    fn collapse_push_pop(input: &mut Vec<Statement>, diagnostics: &mut Diagnostics) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i + 1 < input.len() {
+            if let (ASTNode::Psh(value), ASTNode::Pop(reg @ ASTArg::Reg(_))) =
+                (&input[i].node, &input[i + 1].node)
+            {
+                let mov = ASTNode::Mov(value.clone(), reg.clone());
+                diagnostics.note(
+                    format!(
+                        "Collapsed `{}` and `{}` into `{}`",
+                        input[i].node,
+                        input[i + 1].node,
+                        mov
+                    ),
+                    Some(input[i].loc.clone()),
+                );
+                input[i].node = mov;
+                input.remove(i + 1);
+                changed = true;
+            }
+            i += 1;
+        }
+        changed
+    }
+
+    /// Removes moves of a register into itself.
+    fn remove_redundant_moves(input: &mut Vec<Statement>, diagnostics: &mut Diagnostics) -> bool {
+        let before = input.len();
+        input.retain(|stmt| match &stmt.node {
+            ASTNode::Mov(ASTArg::Reg(src), ASTArg::Reg(dst)) if src == dst => {
+                diagnostics.note(
+                    format!("Removed redundant move `{}`", stmt.node),
+                    Some(stmt.loc.clone()),
+                );
+                false
+            }
+            _ => true,
+        });
+        input.len() != before
+    }
+
+    /// Replaces arithmetic whose operands are all known with a move of the result. Registers
+    /// are known after a literal is moved into them, until something else may change them.
+    fn fold_constants(input: &mut [Statement], diagnostics: &mut Diagnostics) -> bool {
+        let mut changed = false;
+        // the registers whose values are known, the ip, sp and bp registers are never tracked
+        let mut known: HashMap<Register, u16> = HashMap::new();
+        for stmt in input.iter_mut() {
+            let value = |arg: &ASTArg| match arg {
+                ASTArg::Lit(lit) => Some(*lit),
+                ASTArg::Reg(reg) => known.get(reg).copied(),
+                _ => None,
+            };
+            // the register the instruction writes to, and the value written if it's known
+            let (dst, result) = match &stmt.node {
+                ASTNode::Mov(src, ASTArg::Reg(dst)) => (*dst, value(src)),
+                ASTNode::Add(a, b) => (Register::ACC, fold(value(a), value(b), u16::wrapping_add)),
+                // `sub a b` computes b - a
+                ASTNode::Sub(a, b) => (Register::ACC, fold(value(b), value(a), u16::wrapping_sub)),
+                ASTNode::Mul(a, b) => (Register::ACC, fold(value(a), value(b), u16::wrapping_mul)),
+                ASTNode::And(a, b) => (Register::ACC, fold(value(a), value(b), |a, b| a & b)),
+                ASTNode::Or(a, b) => (Register::ACC, fold(value(a), value(b), |a, b| a | b)),
+                ASTNode::Xor(a, b) => (Register::ACC, fold(value(a), value(b), |a, b| a ^ b)),
+                ASTNode::Not(a) => (Register::ACC, value(a).map(|a| !a)),
+                ASTNode::Shl(ASTArg::Reg(reg), b) => (
+                    *reg,
+                    fold(known.get(reg).copied(), value(b), |a, b| {
+                        a.checked_shl(b as u32).unwrap_or(0)
+                    }),
+                ),
+                ASTNode::Shr(ASTArg::Reg(reg), b) => (
+                    *reg,
+                    fold(known.get(reg).copied(), value(b), |a, b| {
+                        a.checked_shr(b as u32).unwrap_or(0)
+                    }),
+                ),
+                ASTNode::Inc(ASTArg::Reg(reg)) => (*reg, known.get(reg).map(|v| v.wrapping_add(1))),
+                ASTNode::Dec(ASTArg::Reg(reg)) => (*reg, known.get(reg).map(|v| v.wrapping_sub(1))),
+                ASTNode::Pop(ASTArg::Reg(reg)) => (*reg, None),
+                // anything else either writes no register, or may write any of them
+                node => {
+                    let writes_nothing = matches!(
+                        node,
+                        ASTNode::Mov(..)
+                            | ASTNode::Psh(_)
+                            | ASTNode::Nop
+                            | ASTNode::Entry(_)
+                            | ASTNode::Comment(_)
+                    ) || node.instruction().is_some_and(|(mnemonic, _)| {
+                        mnemonic.starts_with('j') || mnemonic == "ret" || mnemonic == "hlt"
+                    });
+                    if !writes_nothing {
+                        known.clear();
+                    }
+                    continue;
+                }
+            };
+            if matches!(dst, Register::IP | Register::SP | Register::BP) {
+                continue;
+            }
+            match result {
+                Some(result) => {
+                    known.insert(dst, result);
+                    if !matches!(stmt.node, ASTNode::Mov(..)) {
+                        let mov = ASTNode::Mov(ASTArg::Lit(result), ASTArg::Reg(dst));
+                        diagnostics.note(
+                            format!("Folded `{}` into `{}`", stmt.node, mov),
+                            Some(stmt.loc.clone()),
+                        );
+                        stmt.node = mov;
+                        changed = true;
+                    }
+                }
+                None => {
+                    known.remove(&dst);
+                }
+            }
+        }
+        changed
+    }
+
+    /// Removes the instructions following `hlt`, `jmp` or `ret` that no label leads to.
+    fn remove_unreachable(input: &mut Vec<Statement>, diagnostics: &mut Diagnostics) -> bool {
+        let before = input.len();
+        let mut reachable = true;
+        input.retain(|stmt| {
+            // comments are kept, without making the code after them reachable
+            if let ASTNode::Comment(_) = stmt.node {
+                return true;
+            }
+            if stmt.node.instruction().is_none() {
+                // labels may be jumped to, and data is never executed
+                reachable = true;
+                return true;
+            }
+            if !reachable {
+                diagnostics.note(
+                    format!("Removed unreachable `{}`", stmt.node),
+                    Some(stmt.loc.clone()),
+                );
+                return false;
+            }
+            reachable = !matches!(stmt.node, ASTNode::Hlt | ASTNode::Ret | ASTNode::Jmp(_));
+            true
+        });
+        input.len() != before
+    }
+}
+
+/// Applies the operation if both operands are known.
+fn fold(a: Option<u16>, b: Option<u16>, op: impl Fn(u16, u16) -> u16) -> Option<u16> {
+    Some(op(a?, b?))
+}
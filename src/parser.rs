@@ -1,113 +1,376 @@
-use std::str::FromStr;
+use std::{path::PathBuf, rc::Rc, str::FromStr};
 
-use pest::{iterators::Pair, Parser};
+use pest::{
+    error::{ErrorVariant, LineColLocation},
+    iterators::Pair,
+    Parser,
+};
 use pest_derive::Parser;
 
 use crate::{
     assembler::AssemblerError,
-    ast::{ASTArg, ASTNode},
+    ast::{ASTArg, ASTNode, Address, SourceLoc, Statement},
+    diagnostics::{did_you_mean, suggest, Diagnostic, Diagnostics, Severity},
+    opcodes,
     register::Register,
+    sources::{FileLoader, SourceLoader, SourceSet},
 };
 
+/// The name snippets parsed by [`ASTParser::parse_str`] are reported under.
+const STR_SOURCE_NAME: &str = "<string>";
+
+/// The names of the directives, suggested for misspelled ones.
+pub(crate) const DIRECTIVES: &[&str] = &[".include", ".org", ".entry", ".byte", ".table"];
+
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 pub struct ASTParser;
 
 impl ASTParser {
-    pub fn parse_file(input: &str) -> Result<Vec<ASTNode>, AssemblerError> {
-        let unparsed_file = std::fs::read_to_string(input)?;
+    /// Parses the given file, splicing in the contents of every `.include` directive.
+    pub fn parse_file(input: &str) -> Result<Vec<Statement>, AssemblerError> {
+        Self::parse_file_with(input, &[])
+    }
+
+    /// Parses the given file like [`ASTParser::parse_file`]. Includes are first resolved relative
+    /// to the including file, then against each of the `include_paths` in order.
+    pub fn parse_file_with(
+        input: &str,
+        include_paths: &[PathBuf],
+    ) -> Result<Vec<Statement>, AssemblerError> {
+        let loader = FileLoader {
+            include_paths: include_paths.to_vec(),
+        };
+        Self::parse_with(&loader, input)
+    }
 
-        let mut parser = Self::parse(Rule::file, &unparsed_file)?;
+    /// Parses a snippet of source, which can't include anything.
+    pub fn parse_str(source: &str) -> Result<Vec<Statement>, AssemblerError> {
+        let mut sources = SourceSet::new();
+        sources.add(STR_SOURCE_NAME, source);
+        Self::parse_with(&sources, STR_SOURCE_NAME)
+    }
 
-        let file = parser.next().unwrap();
+    /// Parses the named source, reading it and everything it includes through the loader. Lines
+    /// that don't parse are skipped, so that the error of every one of them is reported at once.
+    pub fn parse_with(
+        loader: &impl SourceLoader,
+        name: &str,
+    ) -> Result<Vec<Statement>, AssemblerError> {
+        let mut diagnostics = Diagnostics::default();
+        let ast = Self::parse_collecting(loader, name, &mut diagnostics)?;
+        match diagnostics.has_errors() {
+            true => Err(diagnostics.into()),
+            false => Ok(ast),
+        }
+    }
 
-        Self::parse_inner(file)
+    /// Parses the named source like [`ASTParser::parse_with`], reporting the errors of the lines
+    /// that don't parse and of the includes that can't be read to `diagnostics`, and returning
+    /// every statement that could be parsed. Only fails if the named source can't be read.
+    pub(crate) fn parse_collecting(
+        loader: &impl SourceLoader,
+        name: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Result<Vec<Statement>, AssemblerError> {
+        let source = loader.load(name)?;
+        let mut stack = vec![(loader.canonical(name)?, Rc::from(name))];
+        Ok(Self::parse_included(
+            loader,
+            &mut stack,
+            &source,
+            diagnostics,
+        ))
     }
 
-    fn parse_inner(rule: Pair<Rule>) -> Result<Vec<ASTNode>, AssemblerError> {
+    /// Parses a single source, recursing into its includes. `stack` holds the canonical name and
+    /// display name of every source currently being parsed, the last one being the one whose
+    /// text is `source`. Includes that can't be read are reported and skipped.
+    fn parse_included(
+        loader: &impl SourceLoader,
+        stack: &mut Vec<(String, Rc<str>)>,
+        source: &str,
+        diagnostics: &mut Diagnostics,
+    ) -> Vec<Statement> {
+        let name = stack.last().unwrap().1.clone();
+        let mut syntax = Diagnostics::default();
+        let statements = Self::parse_lines(source, name.clone(), &mut syntax);
+        // syntax errors are reported along with those of includes, in the order of the lines
+        let mut syntax = syntax.into_iter().peekable();
         let mut ast = Vec::new();
+        for stmt in statements {
+            let target = match &stmt.node {
+                ASTNode::Include(target) => target,
+                _ => {
+                    ast.push(stmt);
+                    continue;
+                }
+            };
+            let at = (stmt.loc.line, stmt.loc.col);
+            while let Some(error) =
+                syntax.next_if(|d| d.loc.as_ref().is_some_and(|loc| (loc.line, loc.col) < at))
+            {
+                diagnostics.push(error);
+            }
+            match Self::open_include(loader, stack, target, &stmt.loc) {
+                Ok((canonical, resolved, included)) => {
+                    stack.push((canonical, resolved));
+                    ast.extend(Self::parse_included(loader, stack, &included, diagnostics));
+                    stack.pop();
+                }
+                Err(e) => diagnostics.push(e.at(stmt.loc).into()),
+            }
+        }
+        diagnostics.extend(syntax);
+        ast
+    }
 
-        for node in rule.into_inner() {
-            match node.as_rule() {
-                Rule::binaryins => {
-                    let mut inner = node.into_inner();
-                    let op = inner.next().unwrap().as_str().to_lowercase();
-                    let left = Self::parse_value(inner.next().unwrap())?;
-                    let right = Self::parse_value(inner.next().unwrap())?;
-                    match op.as_str() {
-                        "mov" => ast.push(ASTNode::Mov(left, right)),
-                        "add" => ast.push(ASTNode::Add(left, right)),
-                        "sub" => ast.push(ASTNode::Sub(left, right)),
-                        "mul" => ast.push(ASTNode::Mul(left, right)),
-                        "shl" => ast.push(ASTNode::Shl(left, right)),
-                        "shr" => ast.push(ASTNode::Shr(left, right)),
-                        "and" => ast.push(ASTNode::And(left, right)),
-                        "or" => ast.push(ASTNode::Or(left, right)),
-                        "xor" => ast.push(ASTNode::Xor(left, right)),
-                        "jne" => ast.push(ASTNode::Jne(left, right)),
-                        "jeq" => ast.push(ASTNode::Jeq(left, right)),
-                        "jlt" => ast.push(ASTNode::Jlt(left, right)),
-                        "jgt" => ast.push(ASTNode::Jgt(left, right)),
-                        "jle" => ast.push(ASTNode::Jle(left, right)),
-                        "jge" => ast.push(ASTNode::Jge(left, right)),
-                        _ => {
-                            return Err(AssemblerError::Parser(format!(
-                                "Unknown binary instruction: {}",
-                                op
-                            )))
-                        }
+    /// Finds and reads the source included as `target` from the last source of `stack`, making
+    /// sure it isn't already being parsed. Returns its canonical name, display name and text.
+    fn open_include(
+        loader: &impl SourceLoader,
+        stack: &[(String, Rc<str>)],
+        target: &str,
+        loc: &SourceLoc,
+    ) -> Result<(String, Rc<str>, String), AssemblerError> {
+        let name = &stack.last().unwrap().1;
+        let resolved = loader
+            .resolve(name, target)
+            .ok_or_else(|| AssemblerError::IncludeNotFound(target.to_string(), loc.clone()))?;
+        let canonical = loader.canonical(&resolved)?;
+        if stack.iter().any(|(c, _)| *c == canonical) {
+            let mut chain: Vec<String> = stack.iter().map(|(_, n)| n.to_string()).collect();
+            chain.push(resolved);
+            return Err(AssemblerError::RecursiveInclude(chain, loc.clone()));
+        }
+        let source = loader.load(&resolved)?;
+        Ok((canonical, Rc::from(resolved), source))
+    }
+
+    /// Parses source text without resolving includes, which are left as [`ASTNode::Include`]
+    /// nodes. `file` is the name used in the locations of the returned statements.
+    pub fn parse_source(source: &str, file: Rc<str>) -> Result<Vec<Statement>, AssemblerError> {
+        let mut diagnostics = Diagnostics::default();
+        let ast = Self::parse_lines(source, file, &mut diagnostics);
+        match diagnostics.has_errors() {
+            true => Err(diagnostics.into()),
+            false => Ok(ast),
+        }
+    }
+
+    /// Parses source text like [`ASTParser::parse_source`], reporting the errors of the lines
+    /// that don't parse and going on with the next one.
+    pub(crate) fn parse_lines(
+        source: &str,
+        file: Rc<str>,
+        diagnostics: &mut Diagnostics,
+    ) -> Vec<Statement> {
+        // lines that don't parse are matched as `invalid`, so the file as a whole always does
+        let parsed = match Self::parse(Rule::file, source) {
+            Ok(mut parser) => parser.next().unwrap(),
+            Err(error) => {
+                diagnostics.push(AssemblerError::from(error.with_path(&file)).into());
+                return vec![];
+            }
+        };
+
+        let mut ast = Vec::new();
+        for node in parsed.into_inner() {
+            if node.as_rule() == Rule::EOI {
+                break;
+            }
+            let (line, col) = node.as_span().start_pos().line_col();
+            let loc = SourceLoc::new(file.clone(), line, col);
+            if node.as_rule() == Rule::invalid {
+                diagnostics.push(Self::syntax_error(node.as_str(), loc));
+                continue;
+            }
+            match Self::parse_node(node) {
+                Ok(parsed) => ast.push(Statement::new(parsed, loc)),
+                Err(error) => diagnostics.push(error.at(loc).into()),
+            }
+        }
+        ast
+    }
+
+    /// Describes why a line doesn't parse, pointing at the place it goes wrong. `loc` is where
+    /// the line starts.
+    fn syntax_error(line: &str, loc: SourceLoc) -> Diagnostic {
+        let error = match Self::parse(Rule::single, line) {
+            Ok(_) => {
+                return Diagnostic::new(Severity::Error, "Syntax error".to_string(), Some(loc))
+            }
+            Err(error) => error,
+        };
+        let col = match error.line_col {
+            LineColLocation::Pos((_, col)) | LineColLocation::Span((_, col), _) => col,
+        };
+        let rest: String = line.chars().skip(col - 1).collect();
+        let message = match error.variant {
+            _ if rest.starts_with("/*") => "Unterminated block comment".to_string(),
+            ErrorVariant::ParsingError { positives, .. } => {
+                let mut expected: Vec<&str> = vec![];
+                for name in positives.iter().filter_map(Self::describe) {
+                    if !expected.contains(&name) {
+                        expected.push(name);
                     }
                 }
-                Rule::unaryins => {
-                    let mut inner = node.into_inner();
-                    let op = inner.next().unwrap().as_str().to_lowercase();
-                    let val = Self::parse_value(inner.next().unwrap())?;
-                    match op.as_str() {
-                        "not" => ast.push(ASTNode::Not(val)),
-                        "jmp" => ast.push(ASTNode::Jmp(val)),
-                        "psh" => ast.push(ASTNode::Psh(val)),
-                        "pop" => ast.push(ASTNode::Pop(val)),
-                        "cal" => ast.push(ASTNode::Cal(val)),
-                        "inc" => ast.push(ASTNode::Inc(val)),
-                        "dec" => ast.push(ASTNode::Dec(val)),
-                        "sys" => ast.push(ASTNode::Sys(val)),
-                        _ => {
-                            return Err(AssemblerError::Parser(format!(
-                                "Unknown unary instruction: {}",
-                                op
-                            )))
-                        }
+                match expected.split_last() {
+                    None => "Syntax error".to_string(),
+                    Some((only, [])) => format!("Syntax error: expected {}", only),
+                    Some((last, rest)) => {
+                        format!("Syntax error: expected {} or {}", rest.join(", "), last)
                     }
                 }
-                Rule::nullaryins => {
-                    let mut inner = node.into_inner();
-                    let op = inner.next().unwrap().as_str().to_lowercase();
-                    match op.as_str() {
-                        "ret" => ast.push(ASTNode::Ret),
-                        "hlt" => ast.push(ASTNode::Hlt),
-                        "nop" => ast.push(ASTNode::Nop),
-                        _ => {
-                            return Err(AssemblerError::Parser(
-                                "Unknown nullary instruction".to_string(),
-                            ))
-                        }
+            }
+            ErrorVariant::CustomError { message } => message,
+        };
+        Diagnostic::new(
+            Severity::Error,
+            message,
+            Some(SourceLoc::new(loc.file, loc.line, loc.col + col - 1)),
+        )
+    }
+
+    /// Names what a rule matches, for syntax errors.
+    fn describe(rule: &Rule) -> Option<&'static str> {
+        match rule {
+            Rule::decnumber
+            | Rule::hexnumber
+            | Rule::octnumber
+            | Rule::binnumber
+            | Rule::negnumber => Some("number"),
+            Rule::charlit => Some("character"),
+            Rule::string => Some("string"),
+            Rule::reg => Some("register"),
+            Rule::word | Rule::localref => Some("label"),
+            Rule::memloc => Some("memory location"),
+            Rule::relative => Some("relative address"),
+            Rule::sign => Some("`+` or `-`"),
+            Rule::scale => Some("scale of 1, 2 or 4"),
+            Rule::comment => Some("comment"),
+            Rule::EOI => Some("end of line"),
+            _ => None,
+        }
+    }
+
+    fn parse_node(node: Pair<Rule>) -> Result<ASTNode, AssemblerError> {
+        let parsed = match node.as_rule() {
+            Rule::binaryins | Rule::unaryins | Rule::nullaryins => {
+                let mut inner = node.into_inner();
+                let op = inner.next().unwrap().as_str().to_lowercase();
+                let args = inner
+                    .map(Self::parse_value)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let found = args.len();
+                match ASTNode::from_instruction(&op, args) {
+                    Some(node) => node,
+                    None => {
+                        return Err(AssemblerError::Parser(
+                            match opcodes::by_mnemonic(&op).next() {
+                                Some(def) => format!(
+                                    "{} expects {} operands, found {}",
+                                    op,
+                                    def.operands.len(),
+                                    found
+                                ),
+                                None => format!(
+                                    "Unknown instruction: {}{}",
+                                    op,
+                                    did_you_mean(suggest(
+                                        &op,
+                                        opcodes::INSTRUCTIONS.iter().map(|def| def.mnemonic)
+                                    ))
+                                ),
+                            },
+                        ))
                     }
                 }
-                Rule::label => {
-                    let label = node.as_span().as_str();
-                    let no_colon = label.trim_end_matches(':');
-                    ast.push(ASTNode::Label(no_colon.to_string()))
-                }
-                Rule::EOI => break,
-                _ => {
-                    return Err(AssemblerError::Parser(format!(
-                        "Unexpected rule: {:?}",
-                        node.as_rule()
-                    )))
+            }
+            Rule::label => {
+                let label = node.as_span().as_str();
+                let no_colon = label.trim_end_matches(':');
+                ASTNode::Label(no_colon.to_string())
+            }
+            Rule::comment => ASTNode::Comment(node.as_str().to_string()),
+            Rule::directive => {
+                let mut inner = node.into_inner();
+                let name = inner.next().unwrap().as_str().to_lowercase();
+                let args: Vec<Pair<Rule>> = inner.collect();
+                match (name.as_str(), args.as_slice()) {
+                    (".include", [path]) if path.as_rule() == Rule::string => {
+                        ASTNode::Include(Self::parse_string(path))
+                    }
+                    (".include", _) => {
+                        return Err(AssemblerError::Parser(
+                            ".include expects a single string argument".to_string(),
+                        ))
+                    }
+                    (".org", [addr]) => ASTNode::Org(Self::parse_value(addr.clone())?),
+                    (".entry", [label]) => ASTNode::Entry(Self::parse_value(label.clone())?),
+                    (".byte" | ".table", [_, ..]) => {
+                        let args = args
+                            .into_iter()
+                            .map(Self::parse_value)
+                            .collect::<Result<Vec<_>, _>>()?;
+                        match name.as_str() {
+                            ".byte" => ASTNode::Byte(args),
+                            _ => ASTNode::Table(args),
+                        }
+                    }
+                    (".byte" | ".table", []) => {
+                        return Err(AssemblerError::Parser(format!(
+                            "{} expects at least one argument",
+                            name
+                        )))
+                    }
+                    (".org" | ".entry", _) => {
+                        return Err(AssemblerError::Parser(format!(
+                            "{} expects a single argument",
+                            name
+                        )))
+                    }
+                    _ => {
+                        return Err(AssemblerError::Parser(format!(
+                            "Unknown directive: {}{}",
+                            name,
+                            did_you_mean(suggest(&name, DIRECTIVES.iter().copied()))
+                        )))
+                    }
                 }
             }
+            _ => {
+                return Err(AssemblerError::Parser(format!(
+                    "Unexpected rule: {:?}",
+                    node.as_rule()
+                )))
+            }
+        };
+        Ok(parsed)
+    }
+
+    /// Strips the quotes off a string literal.
+    fn parse_string(rule: &Pair<Rule>) -> String {
+        String::from_utf8_lossy(&Self::parse_bytes(rule)).into_owned()
+    }
+
+    /// Gets the bytes of a string literal, with its escapes replaced.
+    fn parse_bytes(rule: &Pair<Rule>) -> Vec<u8> {
+        let quoted = rule.as_str();
+        let mut bytes = vec![];
+        let mut rest = &quoted[1..quoted.len() - 1];
+        while let Some(c) = rest.chars().next() {
+            if c == '\\' {
+                let (byte, len) = unescape(rest);
+                bytes.push(byte);
+                rest = &rest[len..];
+            } else {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                rest = &rest[c.len_utf8()..];
+            }
         }
-        Ok(ast)
+        bytes
     }
 
     fn parse_value(rule: Pair<Rule>) -> Result<ASTArg, AssemblerError> {
@@ -131,28 +394,53 @@ impl ASTParser {
                 let value = u16::from_str_radix(trimmed, 2)?;
                 Ok(ASTArg::Lit(value))
             }
+            Rule::negnumber => {
+                let value = rule.as_str().trim_start_matches('-').parse::<u32>()?;
+                Ok(ASTArg::Neg(value))
+            }
+            Rule::charlit => {
+                let quoted = rule.as_str();
+                let inner = &quoted[1..quoted.len() - 1];
+                let c = match inner.starts_with('\\') {
+                    true => char::from(unescape(inner).0),
+                    false => inner.chars().next().unwrap(),
+                };
+                Ok(ASTArg::Char(c))
+            }
+            Rule::string => Ok(ASTArg::Str(Self::parse_bytes(&rule))),
             Rule::reg => {
                 let reg = Register::from_str(rule.as_str())
                     .map_err(|e| AssemblerError::Parser(e.to_string()))?;
                 Ok(ASTArg::Reg(reg))
             }
-            Rule::word => {
+            Rule::word | Rule::localref => {
                 let value = rule.as_str();
                 Ok(ASTArg::Label(value.to_string()))
             }
-            Rule::memloc => {
+            Rule::relative => {
                 let mut inner = rule.into_inner();
-                let memloc_rule = inner.next().unwrap();
-                Ok(ASTArg::Mem(Box::new(Self::parse_value(memloc_rule)?)))
+                let offset = match (inner.next(), inner.next()) {
+                    (Some(sign), Some(number)) => match Self::parse_value(number)? {
+                        ASTArg::Lit(value) if sign.as_str() == "-" => -(value as i32),
+                        ASTArg::Lit(value) => value as i32,
+                        arg => return Err(AssemblerError::InvalidArgument(arg)),
+                    },
+                    _ => 0,
+                };
+                Ok(ASTArg::Rel(offset))
             }
-            Rule::memoff => {
+            Rule::memloc => {
                 let mut inner = rule.into_inner();
-                let num = inner.next().unwrap();
-                let memoff_rule = inner.next().unwrap();
-                Ok(ASTArg::Offset(
-                    Box::new(Self::parse_value(num)?),
-                    Box::new(Self::parse_value(memoff_rule)?),
-                ))
+                let first = inner.next().unwrap();
+                if inner.peek().is_none() && first.as_rule() != Rule::scaled {
+                    return Ok(ASTArg::Mem(Box::new(Self::parse_value(first)?)));
+                }
+                let mut address = Address::default();
+                Self::add_term(&mut address, false, first)?;
+                while let Some(sign) = inner.next() {
+                    Self::add_term(&mut address, sign.as_str() == "-", inner.next().unwrap())?;
+                }
+                Ok(ASTArg::Indexed(Box::new(address)))
             }
             _ => Err(AssemblerError::Parser(format!(
                 "Unknown rule: {:?}",
@@ -160,4 +448,78 @@ impl ASTParser {
             ))),
         }
     }
+
+    /// Adds a term of a memory location to the address it computes, the first register making
+    /// the base and the second one the index. Only numbers may be subtracted.
+    fn add_term(
+        address: &mut Address,
+        negative: bool,
+        term: Pair<Rule>,
+    ) -> Result<(), AssemblerError> {
+        let text = term.as_str().to_string();
+        let error = |message: &str| Err(AssemblerError::Parser(format!("{}: {}", message, text)));
+        let sign = if negative { -1 } else { 1 };
+        // a scaled index register, or any other value
+        let (index, value) = match term.as_rule() {
+            Rule::scaled => {
+                let mut inner = term.into_inner();
+                let reg = Register::from_str(inner.next().unwrap().as_str())
+                    .map_err(|e| AssemblerError::Parser(e.to_string()))?;
+                let scale = inner.next().unwrap().as_str().parse::<u8>()?;
+                (Some((reg, scale)), None)
+            }
+            _ => (None, Some(Self::parse_value(term)?)),
+        };
+        let displacement = match (index, value) {
+            (None, Some(ASTArg::Lit(lit))) => address.displacement as i64 + lit as i64 * sign,
+            (None, Some(ASTArg::Neg(magnitude))) => {
+                address.displacement as i64 - magnitude as i64 * sign
+            }
+            _ if negative => return error("Only numbers can be subtracted in an address"),
+            (Some(_), _) if address.index.is_some() => {
+                return error("An address can only have one index register")
+            }
+            (Some(index), _) => {
+                address.index = Some(index);
+                return Ok(());
+            }
+            (None, Some(ASTArg::Reg(reg))) if address.base.is_none() => {
+                address.base = Some(reg);
+                return Ok(());
+            }
+            (None, Some(ASTArg::Reg(reg))) if address.index.is_none() => {
+                address.index = Some((reg, 1));
+                return Ok(());
+            }
+            (None, Some(ASTArg::Reg(_))) => {
+                return error("An address can use at most two registers")
+            }
+            (None, Some(ASTArg::Label(label))) if address.label.is_none() => {
+                address.label = Some(label);
+                return Ok(());
+            }
+            (None, Some(ASTArg::Label(_))) => {
+                return error("An address can only refer to one label")
+            }
+            _ => return error("Invalid term in address"),
+        };
+        if displacement.unsigned_abs() > u16::MAX as u64 {
+            return error("Displacement doesn't fit in 16 bits");
+        }
+        address.displacement = displacement as i32;
+        Ok(())
+    }
+}
+
+/// Decodes the escape sequence at the start of `text`, returning the byte it stands for and the
+/// length of the sequence. The grammar only lets valid escapes through.
+fn unescape(text: &str) -> (u8, usize) {
+    match text.as_bytes()[1] {
+        b'n' => (b'\n', 2),
+        b't' => (b'\t', 2),
+        b'r' => (b'\r', 2),
+        b'0' => (0, 2),
+        b'x' => (u8::from_str_radix(&text[2..4], 16).unwrap(), 4),
+        escaped => (escaped, 2),
+    }
 }
@@ -1,9 +1,10 @@
 use std::str::FromStr;
+use strum::IntoEnumIterator;
 use strum_macros::{AsRefStr, EnumCount as EnumCountMacro, EnumIter};
 
 use crate::cpu::CpuError;
 
-#[derive(Debug, Copy, Clone, EnumCountMacro, EnumIter, AsRefStr, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, EnumCountMacro, EnumIter, AsRefStr, PartialEq, Eq, Hash)]
 /// Represents the registers available in the CPU.
 pub enum Register {
     IP,  // Instruction pointer
@@ -24,6 +25,11 @@ impl Register {
     pub fn to_index(&self) -> usize {
         *self as usize
     }
+
+    /// Gets the register with the given index, the inverse of [`Register::to_index`].
+    pub fn from_index(index: usize) -> Option<Register> {
+        Register::iter().nth(index)
+    }
 }
 
 impl FromStr for Register {
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+/// Where the parser reads the source files from, and how it finds the files they include.
+pub trait SourceLoader {
+    /// Finds the source an `.include` of `target` in the source named `from` refers to,
+    /// returning its name.
+    fn resolve(&self, from: &str, target: &str) -> Option<String>;
+
+    /// Gets an identity of the named source that is the same for every name it can be
+    /// reached by, used to detect include cycles.
+    fn canonical(&self, name: &str) -> std::io::Result<String>;
+
+    /// Reads the named source.
+    fn load(&self, name: &str) -> std::io::Result<String>;
+}
+
+/// Reads sources from the filesystem. Includes are first resolved relative to the including
+/// file, then against each of the include paths in order.
+pub struct FileLoader {
+    pub include_paths: Vec<PathBuf>,
+}
+
+impl SourceLoader for FileLoader {
+    fn resolve(&self, from: &str, target: &str) -> Option<String> {
+        let target = Path::new(target);
+        if target.is_absolute() {
+            return target
+                .is_file()
+                .then(|| target.to_string_lossy().into_owned());
+        }
+        let base = Path::new(from).parent().unwrap_or_else(|| Path::new(""));
+        std::iter::once(base)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(target))
+            .find(|candidate| candidate.is_file())
+            .map(|found| found.to_string_lossy().into_owned())
+    }
+
+    fn canonical(&self, name: &str) -> std::io::Result<String> {
+        Ok(Path::new(name)
+            .canonicalize()?
+            .to_string_lossy()
+            .into_owned())
+    }
+
+    fn load(&self, name: &str) -> std::io::Result<String> {
+        std::fs::read_to_string(name)
+    }
+}
+
+/// A set of named sources held in memory, which include each other by name. Names are paths,
+/// so an `.include "util.rack"` in `lib/main.rack` refers to `lib/util.rack`, and falls back to
+/// `util.rack` when there's no such source. Like files, included sources are reported by the
+/// name they were included as, such as `lib/../common.rack`.
+#[derive(Debug, Clone, Default)]
+pub struct SourceSet {
+    sources: HashMap<String, String>,
+}
+
+impl SourceSet {
+    pub fn new() -> SourceSet {
+        SourceSet::default()
+    }
+
+    /// Adds a source with the given name, replacing any source with the same name.
+    pub fn add(&mut self, name: &str, source: &str) -> &mut SourceSet {
+        self.sources.insert(normalize(name), source.to_string());
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.sources.get(&normalize(name)).map(String::as_str)
+    }
+}
+
+impl SourceLoader for SourceSet {
+    fn resolve(&self, from: &str, target: &str) -> Option<String> {
+        let base = Path::new(from).parent().unwrap_or_else(|| Path::new(""));
+        [base.join(target), PathBuf::from(target)]
+            .iter()
+            .map(|candidate| candidate.to_string_lossy().into_owned())
+            .find(|candidate| self.get(candidate).is_some())
+    }
+
+    fn canonical(&self, name: &str) -> std::io::Result<String> {
+        Ok(normalize(name))
+    }
+
+    fn load(&self, name: &str) -> std::io::Result<String> {
+        self.get(name).map(str::to_string).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No source named {}", name),
+            )
+        })
+    }
+}
+
+/// Reads one source from memory and every other one through another loader, such as a file
+/// open in an editor whose changes aren't saved yet.
+pub struct Overlay<'a, L: SourceLoader> {
+    pub loader: &'a L,
+    pub name: String,
+    pub source: String,
+}
+
+impl<L: SourceLoader> SourceLoader for Overlay<'_, L> {
+    fn resolve(&self, from: &str, target: &str) -> Option<String> {
+        self.loader.resolve(from, target)
+    }
+
+    fn canonical(&self, name: &str) -> std::io::Result<String> {
+        // the source in memory may not have been saved anywhere yet
+        match name == self.name {
+            true => self
+                .loader
+                .canonical(name)
+                .or_else(|_| Ok(name.to_string())),
+            false => self.loader.canonical(name),
+        }
+    }
+
+    fn load(&self, name: &str) -> std::io::Result<String> {
+        match name == self.name {
+            true => Ok(self.source.clone()),
+            false => self.loader.load(name),
+        }
+    }
+}
+
+/// Removes the `.` and `..` components of a source name, without touching the filesystem.
+fn normalize(name: &str) -> String {
+    let mut parts: Vec<&str> = vec![];
+    for component in Path::new(name).components() {
+        match component {
+            Component::CurDir => (),
+            Component::RootDir => parts.push(""),
+            Component::ParentDir => match parts.last() {
+                // the parent of the root is the root itself, as for files
+                Some(&"") => (),
+                Some(last) if *last != ".." => {
+                    parts.pop();
+                }
+                _ => parts.push(".."),
+            },
+            component => parts.push(component.as_os_str().to_str().unwrap_or_default()),
+        }
+    }
+    parts.join("/")
+}
@@ -0,0 +1,87 @@
+use crate::{
+    cpu::{CpuError, CPU},
+    register::Register,
+};
+
+/// A function of the VM that programs call with `sys`, either by number or by name.
+#[derive(Debug, Clone, Copy)]
+pub struct Syscall {
+    pub number: u8,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub handler: fn(&CPU) -> Result<(), CpuError>,
+}
+
+/// The syscalls every VM provides.
+const BUILTIN_SYSCALLS: &[Syscall] = &[
+    Syscall {
+        number: 0x00,
+        name: "print_acc",
+        description: "Prints the value of the acc register",
+        handler: |cpu| {
+            println!("{}", cpu.get_register(&Register::ACC));
+            Ok(())
+        },
+    },
+    Syscall {
+        number: 0x01,
+        name: "exit",
+        description: "Stops the program, with the value of the acc register as exit status",
+        handler: |cpu| {
+            cpu.exit(cpu.get_register(&Register::ACC));
+            Ok(())
+        },
+    },
+];
+
+/// The set of syscalls known to the assembler and the CPU. The default registry holds the
+/// builtin syscalls, and more can be registered on top of them.
+#[derive(Debug, Clone)]
+pub struct SyscallRegistry {
+    syscalls: Vec<Syscall>,
+}
+
+impl SyscallRegistry {
+    /// Creates a registry without any syscall.
+    pub fn empty() -> SyscallRegistry {
+        SyscallRegistry { syscalls: vec![] }
+    }
+
+    /// Adds the syscall to the registry. Fails with the already registered syscall if the number
+    /// or the name is taken.
+    pub fn register(&mut self, syscall: Syscall) -> Result<(), Syscall> {
+        match self
+            .syscalls
+            .iter()
+            .find(|s| s.number == syscall.number || s.name == syscall.name)
+        {
+            Some(taken) => Err(*taken),
+            None => {
+                let at = self.syscalls.partition_point(|s| s.number < syscall.number);
+                self.syscalls.insert(at, syscall);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn by_number(&self, number: u8) -> Option<&Syscall> {
+        self.syscalls.iter().find(|s| s.number == number)
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Syscall> {
+        self.syscalls.iter().find(|s| s.name == name)
+    }
+
+    /// Iterates over the registered syscalls, ordered by number.
+    pub fn iter(&self) -> impl Iterator<Item = &Syscall> {
+        self.syscalls.iter()
+    }
+}
+
+impl Default for SyscallRegistry {
+    fn default() -> Self {
+        SyscallRegistry {
+            syscalls: BUILTIN_SYSCALLS.to_vec(),
+        }
+    }
+}
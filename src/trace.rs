@@ -0,0 +1,235 @@
+use std::{io::Write, ops::Range};
+
+use serde_json::{json, Map, Value};
+use strum::IntoEnumIterator;
+
+use crate::{
+    cpu::{CpuError, CPU},
+    debugger::parse_number,
+    disassembler::Disassembler,
+    opcodes::OpCode,
+    register::Register,
+};
+
+#[derive(Debug)]
+pub enum TraceError {
+    Cpu(CpuError),
+    Io(std::io::Error),
+    /// A filter is neither a label nor a range of addresses
+    InvalidFilter(String),
+}
+
+impl std::error::Error for TraceError {}
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TraceError::Cpu(e) => write!(f, "{}", e),
+            TraceError::Io(e) => write!(f, "Could not write the trace: {}", e),
+            TraceError::InvalidFilter(filter) => write!(
+                f,
+                "Invalid trace filter: {}, expected a label or <start>..<end>",
+                filter
+            ),
+        }
+    }
+}
+
+impl From<CpuError> for TraceError {
+    fn from(e: CpuError) -> Self {
+        TraceError::Cpu(e)
+    }
+}
+
+impl From<std::io::Error> for TraceError {
+    fn from(e: std::io::Error) -> Self {
+        TraceError::Io(e)
+    }
+}
+
+/// Runs a program while writing what each instruction does as JSON Lines, one object per
+/// executed instruction, with its keys sorted so that traces diff well:
+///
+/// ```json
+/// {"at":"main+4","bytes":"19 00 11","instruction":"cal double","ip":4,
+///  "registers":{"sp":{"new":65531,"old":65533}},"step":2,"syscall":null,
+///  "writes":[{"addr":65533,"bytes":"00 07"}]}
+/// ```
+///
+/// `registers` holds the registers the instruction changed, but ip, which the next record shows.
+/// `at` names the address after the closest label at or before it, and is null without one.
+pub struct Tracer<'a, W: Write> {
+    cpu: &'a CPU,
+    out: W,
+    /// The labels of the program with their addresses
+    symbols: Vec<(String, u16)>,
+    /// The addresses whose instructions are traced, all of them when empty
+    filters: Vec<Range<usize>>,
+    /// The number of instructions executed so far, traced or not
+    steps: u64,
+}
+
+impl<'a, W: Write> Tracer<'a, W> {
+    pub fn new(cpu: &'a CPU, out: W) -> Tracer<'a, W> {
+        Tracer {
+            cpu,
+            out,
+            symbols: vec![],
+            filters: vec![],
+            steps: 0,
+        }
+    }
+
+    /// Names addresses after the given labels, and lets filters use them. See
+    /// [`Assembly::labels`].
+    ///
+    /// [`Assembly::labels`]: crate::assembler::Assembly::labels
+    pub fn with_symbols<'s>(
+        mut self,
+        symbols: impl IntoIterator<Item = (&'s String, &'s u16)>,
+    ) -> Tracer<'a, W> {
+        self.symbols = symbols
+            .into_iter()
+            .map(|(name, addr)| (name.clone(), *addr))
+            .collect();
+        self
+    }
+
+    /// Only traces the instructions in the given place, on top of those already filtered. The
+    /// place is either a range of addresses, `<start>..<end>` with numbers or labels and either
+    /// end left out to leave it open, or a label, covering the code up to the next label outside
+    /// its scope: `main` covers `main.loop`.
+    pub fn filter(&mut self, filter: &str) -> Result<(), TraceError> {
+        let invalid = || TraceError::InvalidFilter(filter.to_string());
+        let range = match filter.split_once("..") {
+            Some((start, end)) => {
+                let start = match start {
+                    "" => 0,
+                    start => self.address(start).ok_or_else(invalid)? as usize,
+                };
+                let end = match end {
+                    "" => self.cpu.memory().len(),
+                    end => self.address(end).ok_or_else(invalid)? as usize,
+                };
+                start..end
+            }
+            None => {
+                let start = self
+                    .symbols
+                    .iter()
+                    .find(|(name, _)| name == filter)
+                    .map(|(_, addr)| *addr)
+                    .ok_or_else(invalid)?;
+                let scope = format!("{}.", filter);
+                let end = self
+                    .symbols
+                    .iter()
+                    .filter(|(name, addr)| *addr > start && !name.starts_with(&scope))
+                    .map(|(_, addr)| *addr as usize)
+                    .min()
+                    .unwrap_or(self.cpu.memory().len());
+                start as usize..end
+            }
+        };
+        self.filters.push(range);
+        Ok(())
+    }
+
+    /// Executes the instruction at ip, tracing it unless it's filtered out. Returns true if the
+    /// program halts.
+    pub fn step(&mut self) -> Result<bool, TraceError> {
+        let ip = self.cpu.get_register(&Register::IP);
+        let traced =
+            self.filters.is_empty() || self.filters.iter().any(|f| f.contains(&(ip as usize)));
+        if !traced {
+            self.steps += 1;
+            return Ok(self.cpu.step()?);
+        }
+
+        let before: Vec<u16> = Register::iter()
+            .map(|reg| self.cpu.get_register(&reg))
+            .collect();
+        let memory = self.cpu.memory();
+        let opcode = memory
+            .get(ip as usize)
+            .ok_or(CpuError::InvalidAddress(ip))
+            .and_then(OpCode::try_from)?;
+        let end = (ip as usize + opcode.def().size()).min(memory.len());
+        let bytes = memory.get_buf(ip as usize, end).unwrap_or_default();
+        let instruction = Disassembler::new(memory)
+            .with_syscalls(self.cpu.syscalls().clone())
+            .with_symbols(self.symbols.iter().map(|(name, addr)| (name, addr)))
+            .disassemble(ip as usize..end)
+            .into_iter()
+            .find(|line| !line.bytes.is_empty())
+            .map_or_else(|| format!("{:?}", opcode), |line| line.node.to_string());
+
+        let halted = self.cpu.step()?;
+        self.steps += 1;
+
+        let mut registers = Map::new();
+        for (reg, old) in Register::iter().zip(before) {
+            let new = self.cpu.get_register(&reg);
+            if reg != Register::IP && new != old {
+                registers.insert(reg.as_ref().to_lowercase(), json!({"old": old, "new": new}));
+            }
+        }
+        let effects = self.cpu.effects();
+        let writes: Vec<Value> = effects
+            .writes
+            .iter()
+            .map(|(addr, bytes)| json!({"addr": addr, "bytes": hex(bytes)}))
+            .collect();
+        let syscall = effects.syscall.map(|number| {
+            let name = self.cpu.syscalls().by_number(number).map(|s| s.name);
+            json!({"number": number, "name": name})
+        });
+        let record = json!({
+            "step": self.steps,
+            "ip": ip,
+            "at": self.name(ip),
+            "bytes": hex(&bytes),
+            "instruction": instruction,
+            "registers": registers,
+            "writes": writes,
+            "syscall": syscall,
+        });
+        writeln!(self.out, "{}", record)?;
+        Ok(halted)
+    }
+
+    /// Runs until the program halts, returning its exit status.
+    pub fn run(&mut self) -> Result<u16, TraceError> {
+        while !self.step()? {}
+        self.out.flush()?;
+        Ok(self.cpu.exit_status().unwrap_or_default())
+    }
+
+    /// Parses an address given as a number or a label.
+    fn address(&self, text: &str) -> Option<u16> {
+        parse_number(text).or_else(|| {
+            self.symbols
+                .iter()
+                .find(|(name, _)| name == text)
+                .map(|(_, addr)| *addr)
+        })
+    }
+
+    /// Names an address after the closest label at or before it, as in `main+4`.
+    fn name(&self, addr: u16) -> Option<String> {
+        let (name, at) = self
+            .symbols
+            .iter()
+            .filter(|(_, at)| *at <= addr)
+            .max_by_key(|(_, at)| *at)?;
+        match addr - at {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+{}", name, offset)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    hex.join(" ")
+}